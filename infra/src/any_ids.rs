@@ -0,0 +1,207 @@
+use std::fmt;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::ids::{Entity, Id, IdParseError, DIVIDER};
+use crate::registry::{self, EntityRegistration};
+use crate::untyped_ids::UntypedId;
+
+/// An id of some entity, where we only find out which when parsing it, such
+/// as `election.0000000000001q5nnvfqq7krfo`.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Hash)]
+pub struct AnyId {
+    prefix: String,
+    id: UntypedId,
+}
+
+impl AnyId {
+    /// Returns an id with the given prefix, which need not belong to a
+    /// registered entity.
+    pub fn new(prefix: &str, id: UntypedId) -> Result<Self, IdParseError> {
+        if prefix.is_empty() {
+            return Err(IdParseError::Unparseable);
+        }
+        if let Some(offset) = prefix.find(DIVIDER) {
+            return Err(IdParseError::invalid_char(prefix, offset));
+        }
+        let prefix = prefix.to_string();
+        Ok(AnyId { prefix, id })
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn untyped(&self) -> UntypedId {
+        self.id
+    }
+
+    /// Returns the typed id, so long as this is an id of a `T`.
+    pub fn downcast<T: Entity>(&self) -> Result<Id<T>, IdParseError> {
+        if self.prefix != T::PREFIX {
+            return Err(IdParseError::InvalidPrefix {
+                expected: T::PREFIX.to_string(),
+                found: self.prefix.clone(),
+            });
+        }
+        Ok(self.id.typed())
+    }
+
+    /// Finds the registered entity that this id belongs to, if any.
+    pub fn entity(&self) -> Option<&'static EntityRegistration> {
+        registry::lookup_prefix(&self.prefix)
+    }
+}
+
+impl<T: Entity> From<Id<T>> for AnyId {
+    fn from(id: Id<T>) -> Self {
+        AnyId {
+            prefix: T::PREFIX.to_string(),
+            id: id.untyped(),
+        }
+    }
+}
+
+impl fmt::Display for AnyId {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{}{}{}", self.prefix, DIVIDER, self.id)
+    }
+}
+
+impl std::str::FromStr for AnyId {
+    type Err = IdParseError;
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        let prefix = match src.find(DIVIDER) {
+            Some(0) => return Err(IdParseError::invalid_char(src, 0)),
+            Some(end) => &src[..end],
+            None => return Err(IdParseError::Unparseable),
+        };
+
+        let id = UntypedId::parse_at(src, prefix.len() + DIVIDER.len())?;
+        let prefix = prefix.to_string();
+
+        Ok(AnyId { prefix, id })
+    }
+}
+
+impl Serialize for AnyId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for AnyId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct IdStrVisitor;
+        impl<'vi> de::Visitor<'vi> for IdStrVisitor {
+            type Value = AnyId;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(formatter, "a prefixed Id string")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<AnyId, E> {
+                value.parse::<AnyId>().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(IdStrVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json;
+
+    #[derive(Debug, Entity)]
+    #[entity(prefix = "songbird")]
+    struct Canary;
+
+    #[derive(Debug, Entity)]
+    #[entity(prefix = "budgie")]
+    struct Budgie;
+
+    #[test]
+    fn should_parse_typed_id_string() {
+        let id = Id::<Canary>::hashed("Hi!");
+        let any = id.to_string().parse::<AnyId>().expect("parse any id");
+
+        assert_eq!(any.prefix(), "songbird");
+        assert_eq!(any.untyped(), id.untyped());
+        assert_eq!(any.to_string(), id.to_string());
+    }
+
+    #[test]
+    fn should_downcast_to_matching_type() {
+        let id = Id::<Canary>::hashed("Hi!");
+        let any = AnyId::from(id);
+
+        assert_eq!(any.downcast::<Canary>().expect("downcast"), id);
+    }
+
+    #[test]
+    fn should_not_downcast_to_other_type() {
+        let any = AnyId::from(Id::<Canary>::hashed("Hi!"));
+
+        let result = any.downcast::<Budgie>();
+        assert!(
+            result.is_err(),
+            "Downcasting {} should return error; got {:?}",
+            any,
+            result
+        );
+    }
+
+    #[test]
+    fn should_build_from_prefix_and_untyped() {
+        let id = Id::<Canary>::hashed("Hi!");
+        let any = AnyId::new("songbird", id.untyped()).expect("new any id");
+
+        assert_eq!(any.downcast::<Canary>().expect("downcast"), id);
+        assert_eq!(
+            AnyId::new("can.ary", id.untyped()),
+            Err(IdParseError::InvalidChar {
+                found: '.',
+                offset: 3
+            })
+        );
+    }
+
+    #[test]
+    fn round_trips_via_serde_json() {
+        let any = AnyId::from(Id::<Canary>::hashed("boo"));
+
+        let json = serde_json::to_string(&any).expect("serde_json::to_string");
+        println!("Json: {}", json);
+        let any2 = serde_json::from_str(&json).expect("serde_json::from_str");
+        assert_eq!(any, any2);
+    }
+
+    #[test]
+    fn should_require_prefix() {
+        for s in &["0000000000001q5nnvfqq7krfo", ".0000000000001q5nnvfqq7krfo"] {
+            let result = s.parse::<AnyId>();
+            assert!(
+                result.is_err(),
+                "Parsing {:?} should return error; got {:?}",
+                s,
+                result,
+            )
+        }
+    }
+
+    #[test]
+    fn should_return_error_on_truncation() {
+        let s = "songbird.0000000000001q5nnvfqq7krf";
+
+        let result = s.parse::<AnyId>();
+
+        assert!(
+            result.is_err(),
+            "Parsing {:?} should return error; got {:?}",
+            s,
+            result,
+        )
+    }
+}
@@ -0,0 +1,24 @@
+-- Applied by `Documents::setup_binary_keys`, after `persistence.sql`. As
+-- there, each statement needs to be over consecutive lines.
+
+SELECT apply_migration(text 'binary-keys 0001 Key documents by kind and binary id', text $$
+    -- Fails, leaving everything as it was, should any id have no id_key.
+    ALTER TABLE documents ADD COLUMN kind TEXT, ADD COLUMN key uuid;
+    UPDATE documents SET kind = split_part(id, '.', 1), key = id_key(id);
+    ALTER TABLE documents ALTER COLUMN kind SET NOT NULL, ALTER COLUMN key SET NOT NULL;
+    -- Hashed ids of different types may share a binary form, so the kind
+    -- is part of the key.
+    ALTER TABLE documents DROP CONSTRAINT documents_pkey;
+    ALTER TABLE documents ADD PRIMARY KEY (kind, key);
+    -- The primary key now covers what this did.
+    DROP INDEX IF EXISTS documents_by_id_time;
+    CREATE OR REPLACE FUNCTION documents_set_key() RETURNS trigger AS $f$
+    BEGIN
+        NEW.kind := split_part(NEW.id, '.', 1);
+        NEW.key := id_key(NEW.id);
+        RETURN NEW;
+    END
+    $f$ LANGUAGE plpgsql;
+    CREATE TRIGGER documents_set_key BEFORE INSERT OR UPDATE OF id ON documents
+        FOR EACH ROW EXECUTE PROCEDURE documents_set_key();
+$$);
@@ -0,0 +1,271 @@
+//! Check symbols for the text form of ids, so that we can catch typos.
+//!
+//! We treat each base32 character as an element of GF(32), and append two
+//! check symbols, such that for the whole string `w`, both `Σ wᵢ` and
+//! `Σ αⁱ·wᵢ` are zero. A single wrong character at position `j` with error
+//! `e` then leaves syndromes of `e` and `αʲ·e`, which tells us `j`.
+
+use crate::ids::{encoded_offset, Entity, Id, IdParseError, DIVIDER, ENCODED_BARE_ID_LEN};
+use crate::untyped_ids::UntypedId;
+
+const ALPHABET: &[u8; 32] = b"0123456789abcdefghijklmnopqrstuv";
+// x⁵ + x² + 1, which is primitive, so α = x generates every non-zero element.
+const POLY: u8 = 0b10_0101;
+const ORDER: usize = 31;
+
+pub(crate) const CHECK_LEN: usize = 2;
+
+/// Why a checked string failed verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Mismatch {
+    /// The string contains a character outside of the alphabet, at the
+    /// given offset.
+    BadChar(usize),
+    /// The check symbols do not match; if exactly one character is wrong,
+    /// this is its offset.
+    Corrupt(Option<usize>),
+}
+
+impl UntypedId {
+    /// Returns the id with two check symbols appended, for places where
+    /// people will copy it by hand.
+    pub fn to_checked_string(&self) -> String {
+        append(&self.to_string())
+    }
+
+    /// Parses the form produced by `to_checked_string`.
+    pub fn from_checked_str(src: &str) -> Result<Self, IdParseError> {
+        Self::parse_checked_at(src, 0)
+    }
+
+    fn parse_checked_at(src: &str, offset: usize) -> Result<Self, IdParseError> {
+        let found = src.len() - offset;
+        if found != ENCODED_BARE_ID_LEN + CHECK_LEN {
+            let expected = ENCODED_BARE_ID_LEN + CHECK_LEN;
+            return Err(IdParseError::InvalidLength { found, expected });
+        }
+        match verify(&src[offset..]) {
+            Ok(encoded) => UntypedId::parse_at(&src[..offset + encoded.len()], offset),
+            Err(Mismatch::BadChar(i)) => Err(IdParseError::invalid_char(src, offset + i)),
+            Err(Mismatch::Corrupt(position)) => Err(IdParseError::ChecksumMismatch {
+                position: position.map(|p| p + offset),
+            }),
+        }
+    }
+}
+
+impl<T: Entity> Id<T> {
+    /// As `UntypedId::to_checked_string`, with the entity prefix.
+    pub fn to_checked_string(&self) -> String {
+        format!(
+            "{}{}{}",
+            T::PREFIX,
+            DIVIDER,
+            self.untyped().to_checked_string()
+        )
+    }
+
+    /// Parses the form produced by `to_checked_string`.
+    pub fn from_checked_str(src: &str) -> Result<Self, IdParseError> {
+        let offset = encoded_offset(src, T::PREFIX)?;
+        Ok(UntypedId::parse_checked_at(src, offset)?.typed())
+    }
+}
+
+/// Returns `encoded` with its check symbols appended.
+pub(crate) fn append(encoded: &str) -> String {
+    let symbols = encoded
+        .bytes()
+        .map(|b| symbol(b).expect("encoded id should only contain base32 characters"))
+        .collect::<Vec<_>>();
+    let (a, b) = syndromes(&symbols);
+
+    // Choose c₀ and c₁ at positions n and n+1, so that the syndromes become
+    // zero: c₀ + c₁ = a, and αⁿ·c₀ + αⁿ⁺¹·c₁ = b.
+    let n = symbols.len();
+    let c1 = div(b ^ mul(pow(n), a), pow(n) ^ pow(n + 1));
+    let c0 = a ^ c1;
+
+    let mut checked = encoded.to_string();
+    checked.push(ALPHABET[c0 as usize] as char);
+    checked.push(ALPHABET[c1 as usize] as char);
+    checked
+}
+
+/// Verifies the check symbols of `checked`, and returns the data portion.
+pub(crate) fn verify(checked: &str) -> Result<&str, Mismatch> {
+    let mut symbols = Vec::with_capacity(checked.len());
+    for (i, b) in checked.bytes().enumerate() {
+        symbols.push(symbol(b).ok_or(Mismatch::BadChar(i))?);
+    }
+    if symbols.len() < CHECK_LEN || symbols.len() > ORDER {
+        return Err(Mismatch::Corrupt(None));
+    }
+
+    match syndromes(&symbols) {
+        (0, 0) => Ok(&checked[..checked.len() - CHECK_LEN]),
+        (0, _) | (_, 0) => Err(Mismatch::Corrupt(None)),
+        (s0, s1) => {
+            let position = log(div(s1, s0)).filter(|&j| j < symbols.len());
+            Err(Mismatch::Corrupt(position))
+        }
+    }
+}
+
+fn symbol(b: u8) -> Option<u8> {
+    let b = b.to_ascii_lowercase();
+    ALPHABET.iter().position(|&c| c == b).map(|p| p as u8)
+}
+
+fn syndromes(symbols: &[u8]) -> (u8, u8) {
+    symbols
+        .iter()
+        .enumerate()
+        .fold((0, 0), |(s0, s1), (i, &w)| (s0 ^ w, s1 ^ mul(pow(i), w)))
+}
+
+fn mul(a: u8, b: u8) -> u8 {
+    let (mut a, mut b, mut product) = (a, b, 0);
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a <<= 1;
+        if a & 0b10_0000 != 0 {
+            a ^= POLY;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+// αⁱ
+fn pow(i: usize) -> u8 {
+    (0..i % ORDER).fold(1, |acc, _| mul(acc, 2))
+}
+
+fn div(a: u8, b: u8) -> u8 {
+    debug_assert!(b != 0, "division by zero");
+    // b⁻¹ = b³⁰, as every non-zero element has order dividing 31.
+    let inverse = (0..ORDER - 1).fold(1, |acc, _| mul(acc, b));
+    mul(a, inverse)
+}
+
+// The `i` such that αⁱ = a.
+fn log(a: u8) -> Option<usize> {
+    (0..ORDER).find(|&i| pow(i) == a)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ids::IdGen;
+
+    #[derive(Debug, Entity)]
+    #[entity(prefix = "checked")]
+    struct Ballot;
+
+    const SOME_ID: &str = "0000000000001q5nnvfqq7krfo";
+
+    #[test]
+    fn should_verify_appended_check_symbols() {
+        let checked = append(SOME_ID);
+        assert_eq!(checked.len(), SOME_ID.len() + CHECK_LEN);
+        assert_eq!(verify(&checked), Ok(SOME_ID));
+    }
+
+    #[test]
+    fn should_locate_any_single_substitution() {
+        let checked = append(SOME_ID);
+        for position in 0..checked.len() {
+            for &replacement in ALPHABET.iter() {
+                let mut corrupted = checked.clone().into_bytes();
+                if corrupted[position] == replacement {
+                    continue;
+                }
+                corrupted[position] = replacement;
+                let corrupted = String::from_utf8(corrupted).expect("utf8");
+
+                assert_eq!(
+                    verify(&corrupted),
+                    Err(Mismatch::Corrupt(Some(position))),
+                    "Corrupted {:?} to {:?}",
+                    checked,
+                    corrupted
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn should_detect_adjacent_transposition() {
+        let checked = append(SOME_ID);
+        let mut swapped = checked.clone().into_bytes();
+        swapped.swap(12, 13);
+        let swapped = String::from_utf8(swapped).expect("utf8");
+
+        assert!(verify(&swapped).is_err(), "{:?} -> {:?}", checked, swapped);
+    }
+
+    #[test]
+    fn should_report_bad_characters() {
+        let mut checked = append(SOME_ID);
+        checked.replace_range(3..4, "!");
+        assert_eq!(verify(&checked), Err(Mismatch::BadChar(3)));
+    }
+
+    #[test]
+    fn should_ignore_case() {
+        let checked = append(SOME_ID).to_uppercase();
+        assert!(verify(&checked).is_ok());
+    }
+
+    #[test]
+    fn ids_round_trip_via_checked_string() {
+        let id = IdGen::new().generate::<Ballot>();
+        let s = id.to_checked_string();
+        assert!(s.starts_with(&id.to_string()), "{:?} from {}", s, id);
+        assert_eq!(Id::<Ballot>::from_checked_str(&s).expect("parse"), id);
+
+        let untyped = id.untyped();
+        let s = untyped.to_checked_string();
+        assert_eq!(UntypedId::from_checked_str(&s).expect("parse"), untyped);
+    }
+
+    #[test]
+    fn should_reject_typo_with_position_in_full_string() {
+        let id = IdGen::new().generate::<Ballot>();
+        let mut s = id.to_checked_string().into_bytes();
+        let position = "checked.".len() + 20;
+        s[position] = if s[position] == b'a' { b'b' } else { b'a' };
+        let s = String::from_utf8(s).expect("utf8");
+
+        let err = Id::<Ballot>::from_checked_str(&s).expect_err("parse corrupted id");
+        assert_eq!(
+            err,
+            IdParseError::ChecksumMismatch {
+                position: Some(position)
+            }
+        );
+    }
+
+    #[test]
+    fn should_reject_unchecked_form() {
+        let id = IdGen::new().generate::<Ballot>();
+        let result = Id::<Ballot>::from_checked_str(&id.to_string());
+        assert_eq!(
+            result,
+            Err(IdParseError::InvalidLength {
+                found: ENCODED_BARE_ID_LEN,
+                expected: ENCODED_BARE_ID_LEN + CHECK_LEN
+            })
+        );
+    }
+
+    #[test]
+    fn field_should_have_inverses() {
+        for a in 1..32 {
+            assert_eq!(mul(a, div(1, a)), 1, "inverse of {}", a);
+        }
+    }
+}
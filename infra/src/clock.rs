@@ -0,0 +1,63 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// A source of the current time, so that we can control it in tests.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// Reads the system's wall clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+/// A clock that only moves when told to. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+impl ManualClock {
+    pub fn new(start: SystemTime) -> Self {
+        let now = Arc::new(Mutex::new(start));
+        ManualClock { now }
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().expect("lock clock") = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().expect("lock clock") += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().expect("lock clock")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn manual_clock_should_only_move_when_told() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let clock = ManualClock::new(start);
+        let other = clock.clone();
+
+        assert_eq!(clock.now(), start);
+        other.advance(Duration::from_secs(5));
+        assert_eq!(clock.now(), start + Duration::from_secs(5));
+        other.set(start);
+        assert_eq!(clock.now(), start);
+    }
+}
@@ -1,11 +1,11 @@
-use std::cmp::Eq;
-use std::collections::HashSet;
-use std::hash::Hash;
 use std::marker::PhantomData;
 
 use serde::{Deserialize, Serialize};
 
-use crate::ids::{Entity, Id};
+use crate::ids::{Entity, Id, IdGen};
+use crate::untyped_ids::UntypedId;
+
+pub use infra_derive::HasMeta;
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Default, Hash)]
 pub struct Version(u64);
@@ -26,10 +26,32 @@ pub trait HasMeta<T> {
     fn meta_mut(&mut self) -> &mut DocMeta<T>;
 }
 
+pub trait HasMailBox<A> {
+    fn mailbox(&self) -> &MailBox<A>;
+    fn mailbox_mut(&mut self) -> &mut MailBox<A>;
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct MailBox<A: Eq + Hash> {
+pub struct MailBox<A> {
     #[serde(rename = "_outgoing")]
-    pub(super) outgoing: HashSet<A>,
+    pub(super) outgoing: Vec<Envelope<A>>,
+    #[serde(rename = "_outgoing_seq", default)]
+    pub(super) next_seq: u64,
+}
+
+/// An outgoing message, along with the information needed to deliver it in
+/// order and to trace it back to whatever caused it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope<A> {
+    /// Uniquely identifies this message.
+    pub id: UntypedId,
+    /// Position of this message amongst those sent by the same document.
+    pub seq: u64,
+    /// Shared by every message in a chain, and taken from the first.
+    pub correlation_id: UntypedId,
+    /// The message that this one was sent in response to, if any.
+    pub causation_id: Option<UntypedId>,
+    pub message: A,
 }
 
 impl<T> DocMeta<T> {
@@ -48,19 +70,92 @@ impl<T> DocMeta<T> {
     }
 }
 
-impl<A: Hash + Eq> MailBox<A> {
+impl<A> MailBox<A> {
     pub fn empty() -> Self {
-        let outgoing = HashSet::new();
+        let outgoing = Vec::new();
+        let next_seq = 0;
+
+        MailBox { outgoing, next_seq }
+    }
 
-        MailBox { outgoing }
+    /// Enqueues a message that starts a new chain; so its correlation id
+    /// will be its own id.
+    pub fn send(&mut self, idgen: &IdGen, msg: A) -> UntypedId {
+        let id = idgen.untyped();
+        self.enqueue(id, id, None, msg)
     }
 
-    pub fn send(&mut self, msg: A) {
-        self.outgoing.insert(msg);
+    /// Enqueues a message sent in response to `cause`, continuing its chain.
+    pub fn send_caused_by<B>(&mut self, idgen: &IdGen, cause: &Envelope<B>, msg: A) -> UntypedId {
+        let id = idgen.untyped();
+        self.enqueue(id, cause.correlation_id, Some(cause.id), msg)
+    }
+
+    /// Enqueues a message as part of an existing chain, such as when a
+    /// process acts on its own behalf rather than in response to a message.
+    pub fn send_correlated(
+        &mut self,
+        idgen: &IdGen,
+        correlation_id: UntypedId,
+        msg: A,
+    ) -> UntypedId {
+        let id = idgen.untyped();
+        self.enqueue(id, correlation_id, None, msg)
+    }
+
+    /// Returns the pending messages in the order they were sent.
+    pub fn outgoing(&self) -> impl Iterator<Item = &Envelope<A>> {
+        self.outgoing.iter()
+    }
+
+    /// Removes and returns the pending messages in the order they were sent.
+    pub fn drain(&mut self) -> impl Iterator<Item = Envelope<A>> + '_ {
+        self.outgoing.drain(..)
+    }
+
+    /// Removes and returns the next message to be delivered.
+    pub fn pop_front(&mut self) -> Option<Envelope<A>> {
+        if self.outgoing.is_empty() {
+            None
+        } else {
+            Some(self.outgoing.remove(0))
+        }
+    }
+
+    /// Puts a previously sent message back in the queue, keeping its
+    /// original identifiers. It goes back in its place by `seq`, so that it
+    /// is delivered before anything that we sent after it.
+    pub(crate) fn requeue(&mut self, envelope: Envelope<A>) {
+        let pos = self
+            .outgoing
+            .binary_search_by_key(&envelope.seq, |e| e.seq)
+            .unwrap_or_else(|pos| pos);
+        self.outgoing.insert(pos, envelope);
+    }
+
+    fn enqueue(
+        &mut self,
+        id: UntypedId,
+        correlation_id: UntypedId,
+        causation_id: Option<UntypedId>,
+        message: A,
+    ) -> UntypedId {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        self.outgoing.push(Envelope {
+            id,
+            seq,
+            correlation_id,
+            causation_id,
+            message,
+        });
+
+        id
     }
 }
 
-impl<A: Eq + Hash> Default for MailBox<A> {
+impl<A> Default for MailBox<A> {
     fn default() -> Self {
         Self::empty()
     }
@@ -69,6 +164,10 @@ impl<A: Eq + Hash> Default for MailBox<A> {
 #[cfg(test)]
 mod test {
     use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Numbered(u32);
+
     #[test]
     fn document_messaging_scratch_pad() {
         #[derive(Debug, Default, Hash, PartialEq, Eq)]
@@ -81,7 +180,7 @@ mod test {
         };
         impl Source {
             fn provoke(&mut self) {
-                self.mbox.send(Message);
+                self.mbox.send(&IdGen::new(), Message);
             }
         }
         impl Dest {
@@ -97,13 +196,104 @@ mod test {
         src.provoke();
 
         // A miracle occurs!
-        for msg in src.mbox.outgoing.drain() {
+        for msg in src.mbox.drain() {
             println!("Message  {:?}", msg);
             // Handler
-            dst.receive(msg);
+            dst.receive(msg.message);
         }
 
         // ... A miracle has now occurred. Honest.
         assert_eq!(dst.items, 1);
     }
+
+    #[test]
+    fn should_deliver_in_send_order() {
+        let idgen = IdGen::new();
+        let mut mbox = MailBox::default();
+
+        for i in 0..8 {
+            mbox.send(&idgen, Numbered(i));
+        }
+
+        let msgs = mbox.drain().map(|e| e.message).collect::<Vec<_>>();
+        assert_eq!(msgs, (0..8).map(Numbered).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn should_keep_equal_messages_distinct() {
+        let idgen = IdGen::new();
+        let mut mbox = MailBox::default();
+
+        let a = mbox.send(&idgen, Numbered(1));
+        let b = mbox.send(&idgen, Numbered(1));
+
+        let envelopes = mbox.drain().collect::<Vec<_>>();
+        assert_eq!(envelopes.len(), 2, "Envelopes: {:?}", envelopes);
+        assert_ne!(a, b);
+        assert_eq!(
+            envelopes.iter().map(|e| e.seq).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+    }
+
+    #[test]
+    fn should_continue_sequence_after_drain() {
+        let idgen = IdGen::new();
+        let mut mbox = MailBox::default();
+
+        mbox.send(&idgen, Numbered(1));
+        mbox.drain().for_each(drop);
+        mbox.send(&idgen, Numbered(2));
+
+        let seqs = mbox.outgoing().map(|e| e.seq).collect::<Vec<_>>();
+        assert_eq!(seqs, vec![1]);
+    }
+
+    #[test]
+    fn should_requeue_in_sequence_order() {
+        let idgen = IdGen::new();
+        let mut mbox = MailBox::default();
+
+        for i in 0..3 {
+            mbox.send(&idgen, Numbered(i));
+        }
+        let first = mbox.pop_front().expect("first");
+        mbox.send(&idgen, Numbered(3));
+        mbox.requeue(first);
+
+        let seqs = mbox.outgoing().map(|e| e.seq).collect::<Vec<_>>();
+        assert_eq!(seqs, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn should_start_new_chain_on_send() {
+        let idgen = IdGen::new();
+        let mut mbox = MailBox::default();
+
+        mbox.send(&idgen, Numbered(1));
+
+        let env = mbox.drain().next().expect("envelope");
+        assert_eq!(env.correlation_id, env.id);
+        assert_eq!(env.causation_id, None);
+    }
+
+    #[test]
+    fn should_trace_chain_across_mailboxes() {
+        let idgen = IdGen::new();
+        let mut first = MailBox::default();
+        let mut second = MailBox::default();
+        let mut third = MailBox::default();
+
+        first.send(&idgen, Numbered(1));
+        let a = first.drain().next().expect("first envelope");
+        second.send_caused_by(&idgen, &a, Numbered(2));
+        let b = second.drain().next().expect("second envelope");
+        third.send_caused_by(&idgen, &b, Numbered(3));
+        let c = third.drain().next().expect("third envelope");
+
+        assert_eq!(b.correlation_id, a.id);
+        assert_eq!(c.correlation_id, a.id);
+        assert_eq!(b.causation_id, Some(a.id));
+        assert_eq!(c.causation_id, Some(b.id));
+    }
 }
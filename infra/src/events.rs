@@ -0,0 +1,361 @@
+use std::time::{Duration, SystemTime};
+
+use failure::Error;
+use log::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::ids::{Entity, Id};
+use crate::persistence::{ConcurrencyError, Documents, Jsonb};
+
+/// A document whose state is derived by folding over the events that have
+/// been appended to its stream, rather than being stored directly.
+pub trait EventSourced: Sized + Serialize + DeserializeOwned + Entity {
+    type Event: Serialize + DeserializeOwned;
+
+    /// The state of a stream that has no events.
+    fn initial(id: Id<Self>) -> Self;
+    fn apply(&mut self, event: &Self::Event);
+}
+
+/// The current state of a stream, and the position of the last event that
+/// was folded into it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stream<S> {
+    pub position: u64,
+    pub state: S,
+}
+
+/// An event, as stored in its stream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedEvent<E> {
+    pub position: u64,
+    pub recorded_at: SystemTime,
+    pub event: E,
+}
+
+// Snapshots are kept in the documents table, so must carry an `_id`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound = "S: EventSourced")]
+struct Snapshot<S> {
+    #[serde(rename = "_id")]
+    id: Id<S>,
+    #[serde(rename = "_position")]
+    position: u64,
+    state: S,
+}
+
+const LOAD_POSITION_SQL: &str = "SELECT COALESCE(MAX(position), 0) FROM events
+                                    WHERE stream_id = $1";
+const APPEND_EVENT_SQL: &str = "INSERT INTO events (stream_id, position, body)
+                                    VALUES ($1, $2, $3)
+                                    ON CONFLICT DO NOTHING";
+const LOAD_EVENTS_SQL: &str =
+    "SELECT position, (extract(epoch FROM recorded_at) * 1000000)::int8, body
+                                    FROM events
+                                    WHERE stream_id = $1
+                                    AND position > $2
+                                    ORDER BY position";
+const LOAD_SNAPSHOT_SQL: &str = "SELECT body FROM documents WHERE id = $1";
+// Only ever move a snapshot forwards, so that a slow writer cannot replace a
+// newer snapshot with an older one.
+const SAVE_SNAPSHOT_SQL: &str = "INSERT INTO documents AS d (id, body)
+                                    VALUES ($1, $2)
+                                    ON CONFLICT (id) DO UPDATE
+                                        SET body = EXCLUDED.body
+                                        WHERE (d.body ->> '_position')::bigint
+                                            < (EXCLUDED.body ->> '_position')::bigint";
+
+impl Documents {
+    /// Appends `events` to the stream `id`, so long as the last event in the
+    /// stream is at `expected_position`; otherwise, we return
+    /// `ConcurrencyError`. Returns the position of the last event appended.
+    pub fn append_events<S: EventSourced>(
+        &self,
+        id: &Id<S>,
+        expected_position: u64,
+        events: &[S::Event],
+    ) -> Result<u64, Error> {
+        let t = self.connection.transaction()?;
+        let stream_id = id.to_string();
+
+        let current: i64 = {
+            let rows = t.prepare_cached(LOAD_POSITION_SQL)?.query(&[&stream_id])?;
+            rows.iter().next().map(|row| row.get(0)).unwrap_or(0)
+        };
+        if current as u64 != expected_position {
+            debug!(
+                "Stream {} at {}; expected {}",
+                stream_id, current, expected_position
+            );
+            return Err(ConcurrencyError.into());
+        }
+
+        let mut position = expected_position;
+        {
+            let append = t.prepare_cached(APPEND_EVENT_SQL)?;
+            for event in events {
+                position += 1;
+                let rows = append.execute(&[&stream_id, &(position as i64), &Jsonb(event)])?;
+                // Someone else appended at this position since we looked.
+                if rows == 0 {
+                    return Err(ConcurrencyError.into());
+                }
+            }
+        }
+        t.commit()?;
+
+        debug!("Appended {} events to {}", events.len(), stream_id);
+        Ok(position)
+    }
+
+    /// Loads the events in stream `id` after `position`, in order. Passing a
+    /// position of zero returns the whole history of the stream.
+    pub fn load_events<S: EventSourced>(
+        &self,
+        id: &Id<S>,
+        position: u64,
+    ) -> Result<Vec<RecordedEvent<S::Event>>, Error> {
+        let load = self.connection.prepare_cached(LOAD_EVENTS_SQL)?;
+        let rows = load.query(&[&id.to_string(), &(position as i64)])?;
+
+        let events = rows
+            .iter()
+            .map(|row| {
+                let position: i64 = row.get(0);
+                let recorded_micros: i64 = row.get(1);
+                let Jsonb(event) = row.get(2);
+                RecordedEvent {
+                    position: position as u64,
+                    recorded_at: SystemTime::UNIX_EPOCH
+                        + Duration::from_micros(recorded_micros as u64),
+                    event,
+                }
+            })
+            .collect();
+        Ok(events)
+    }
+
+    /// Rebuilds the current state of stream `id`, starting from the latest
+    /// snapshot, if there is one.
+    pub fn load_stream<S: EventSourced>(&self, id: &Id<S>) -> Result<Stream<S>, Error> {
+        let mut stream = match self.load_snapshot(id)? {
+            Some(Snapshot {
+                position, state, ..
+            }) => Stream { position, state },
+            None => Stream {
+                position: 0,
+                state: S::initial(*id),
+            },
+        };
+
+        let events = self.load_events(id, stream.position)?;
+        trace!(
+            "Folding {} events into {} from {}",
+            events.len(),
+            id,
+            stream.position
+        );
+        for recorded in events {
+            stream.state.apply(&recorded.event);
+            stream.position = recorded.position;
+        }
+
+        Ok(stream)
+    }
+
+    /// Caches the current state of stream `id`, so that later loads need
+    /// only apply the events appended since. Returns the position of the
+    /// snapshot.
+    pub fn snapshot_stream<S: EventSourced>(&self, id: &Id<S>) -> Result<u64, Error> {
+        let Stream { position, state } = self.load_stream(id)?;
+        let snapshot = Snapshot {
+            id: *id,
+            position,
+            state,
+        };
+
+        let rows = self
+            .connection
+            .prepare_cached(SAVE_SNAPSHOT_SQL)?
+            .execute(&[&id.to_string(), &Jsonb(&snapshot)])?;
+        debug!("Saved snapshot of {} at {}: {} rows", id, position, rows);
+
+        Ok(position)
+    }
+
+    fn load_snapshot<S: EventSourced>(&self, id: &Id<S>) -> Result<Option<Snapshot<S>>, Error> {
+        let load = self.connection.prepare_cached(LOAD_SNAPSHOT_SQL)?;
+        let rows = load.query(&[&id.to_string()])?;
+
+        Ok(rows.iter().next().map(|row| {
+            let Jsonb(snapshot) = row.get(0);
+            snapshot
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{pool, IDGEN};
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Entity)]
+    #[entity(prefix = "tally")]
+    struct Tally {
+        id: Id<Tally>,
+        yes: u64,
+        no: u64,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    enum Vote {
+        Yes,
+        No,
+    }
+
+    impl EventSourced for Tally {
+        type Event = Vote;
+
+        fn initial(id: Id<Self>) -> Self {
+            Tally { id, yes: 0, no: 0 }
+        }
+
+        fn apply(&mut self, event: &Vote) {
+            match event {
+                Vote::Yes => self.yes += 1,
+                Vote::No => self.no += 1,
+            }
+        }
+    }
+
+    #[test]
+    fn should_load_empty_stream_as_initial() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("events_should_load_empty_stream_as_initial")?;
+        let docs = pool.get()?;
+
+        let id = IDGEN.generate();
+        let stream = docs.load_stream::<Tally>(&id)?;
+        assert_eq!(
+            stream,
+            Stream {
+                position: 0,
+                state: Tally::initial(id)
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_fold_appended_events() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("events_should_fold_appended_events")?;
+        let docs = pool.get()?;
+
+        let id = IDGEN.generate::<Tally>();
+        let pos = docs.append_events(&id, 0, &[Vote::Yes, Vote::No])?;
+        let pos = docs.append_events(&id, pos, &[Vote::Yes])?;
+        assert_eq!(pos, 3);
+
+        let stream = docs.load_stream(&id)?;
+        assert_eq!(stream.position, 3);
+        assert_eq!((stream.state.yes, stream.state.no), (2, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn should_fail_on_stale_position() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("events_should_fail_on_stale_position")?;
+        let docs = pool.get()?;
+
+        let id = IDGEN.generate::<Tally>();
+        docs.append_events(&id, 0, &[Vote::Yes])?;
+        let err = docs
+            .append_events(&id, 0, &[Vote::No])
+            .expect_err("append at stale position");
+        assert_eq!(
+            err.downcast_ref::<ConcurrencyError>(),
+            Some(&ConcurrencyError)
+        );
+
+        let err = docs
+            .append_events(&id, 2, &[Vote::No])
+            .expect_err("append past end of stream");
+        assert_eq!(
+            err.downcast_ref::<ConcurrencyError>(),
+            Some(&ConcurrencyError)
+        );
+
+        assert_eq!(docs.load_stream(&id)?.position, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn should_replay_history_in_order() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("events_should_replay_history_in_order")?;
+        let docs = pool.get()?;
+
+        let id = IDGEN.generate::<Tally>();
+        docs.append_events(&id, 0, &[Vote::No, Vote::Yes, Vote::No])?;
+
+        let history = docs
+            .load_events(&id, 0)?
+            .into_iter()
+            .map(|r| (r.position, r.event))
+            .collect::<Vec<_>>();
+        assert_eq!(history, vec![(1, Vote::No), (2, Vote::Yes), (3, Vote::No)]);
+
+        let rest = docs.load_events(&id, 2)?;
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].event, Vote::No);
+        Ok(())
+    }
+
+    #[test]
+    fn should_load_from_snapshot() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("events_should_load_from_snapshot")?;
+        let docs = pool.get()?;
+
+        let id = IDGEN.generate::<Tally>();
+        docs.append_events(&id, 0, &[Vote::Yes, Vote::Yes])?;
+        assert_eq!(docs.snapshot_stream(&id)?, 2);
+        docs.append_events(&id, 2, &[Vote::No])?;
+
+        // Only the snapshot knows about the first two events now.
+        docs.connection.execute(
+            "DELETE FROM events WHERE stream_id = $1 AND position <= 2",
+            &[&id.to_string()],
+        )?;
+
+        let stream = docs.load_stream(&id)?;
+        assert_eq!(stream.position, 3);
+        assert_eq!((stream.state.yes, stream.state.no), (2, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_replace_newer_snapshot() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("events_should_not_replace_newer_snapshot")?;
+        let docs = pool.get()?;
+
+        let id = IDGEN.generate::<Tally>();
+        docs.append_events(&id, 0, &[Vote::Yes, Vote::No])?;
+        docs.snapshot_stream(&id)?;
+
+        let stale = Snapshot {
+            id,
+            position: 1,
+            state: Tally { id, yes: 1, no: 0 },
+        };
+        docs.connection
+            .execute(SAVE_SNAPSHOT_SQL, &[&id.to_string(), &Jsonb(&stale)])?;
+
+        let snapshot = docs.load_snapshot(&id)?.expect("snapshot");
+        assert_eq!(snapshot.position, 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+use failure::{bail, Error};
+use serde::de::DeserializeOwned;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::ids::{Entity, Id, IdGen};
+use crate::persistence::Documents;
+use crate::untyped_ids::UntypedId;
+
+const KEY_LEN: usize = 16;
+
+/// A secret key for generating hashed ids, so that only we can work out the
+/// id for a given entity. Written as 32 hex digits in config.
+#[derive(Clone, PartialEq, Eq)]
+pub struct HashKey {
+    pub(crate) k0: u64,
+    pub(crate) k1: u64,
+}
+
+/// The key we generate hashed ids with, along with any we used previously,
+/// so that we can still find documents whose ids were generated with those.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HashKeys {
+    pub current: HashKey,
+    #[serde(default)]
+    pub previous: Vec<HashKey>,
+}
+
+impl HashKey {
+    pub fn generate() -> Self {
+        let k0 = rand::random();
+        let k1 = rand::random();
+        HashKey { k0, k1 }
+    }
+
+    /// Returns the key in the form we expect in config.
+    pub fn to_hex(&self) -> String {
+        let mut bytes = Vec::with_capacity(KEY_LEN);
+        bytes.extend(&self.k0.to_be_bytes());
+        bytes.extend(&self.k1.to_be_bytes());
+        HEXLOWER.encode(&bytes)
+    }
+}
+
+impl IdGen {
+    /// Returns an id nominally at time zero, with a random portion derived
+    /// from the entity type, the given entity and `key`.
+    pub fn hashed_with<T: Entity, H: Hash>(key: &HashKey, entity: H) -> Id<T> {
+        UntypedId::hashed_with(key, T::PREFIX, entity).typed()
+    }
+}
+
+impl UntypedId {
+    /// As `hashed`, but keyed, and separated by `domain`, so that the same
+    /// entity hashes to unrelated ids for different types.
+    pub fn hashed_with<H: Hash>(key: &HashKey, domain: &str, entity: H) -> Self {
+        let stamp = 0;
+
+        let mut h = siphasher::sip::SipHasher24::new_with_keys(key.k0, key.k1);
+        domain.hash(&mut h);
+        entity.hash(&mut h);
+        let random = h.finish();
+
+        UntypedId { stamp, random }
+    }
+}
+
+impl HashKeys {
+    /// Returns the id for `entity` under the current key.
+    pub fn hashed<T: Entity, H: Hash>(&self, entity: H) -> Id<T> {
+        IdGen::hashed_with(&self.current, entity)
+    }
+
+    /// Returns the ids that `entity` may have been given, starting with the
+    /// current key, followed by previous keys.
+    pub fn candidates<T: Entity, H: Hash>(&self, entity: H) -> Vec<Id<T>> {
+        std::iter::once(&self.current)
+            .chain(self.previous.iter())
+            .map(|key| IdGen::hashed_with(key, &entity))
+            .collect()
+    }
+}
+
+impl Documents {
+    /// Loads the document with a hashed id for `entity`, trying each of
+    /// `keys` in turn.
+    pub fn load_hashed<D: DeserializeOwned + Entity, H: Hash>(
+        &self,
+        keys: &HashKeys,
+        entity: H,
+    ) -> Result<Option<D>, Error> {
+        for id in keys.candidates::<D, _>(entity) {
+            if let Some(doc) = self.load(&id)? {
+                return Ok(Some(doc));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl fmt::Debug for HashKey {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "HashKey(..)")
+    }
+}
+
+impl std::str::FromStr for HashKey {
+    type Err = Error;
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        let bytes = HEXLOWER_PERMISSIVE
+            .decode(src.as_bytes())
+            .map_err(|e| failure::format_err!("{}", e))?;
+        if bytes.len() != KEY_LEN {
+            bail!("Expected {} key bytes; got {}", KEY_LEN, bytes.len());
+        }
+        let k0 = u64::from_be_bytes(bytes[0..8].try_into().expect("k0 bytes"));
+        let k1 = u64::from_be_bytes(bytes[8..16].try_into().expect("k1 bytes"));
+        Ok(HashKey { k0, k1 })
+    }
+}
+
+impl Serialize for HashKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+impl<'de> Deserialize<'de> for HashKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct KeyStrVisitor;
+        impl<'vi> de::Visitor<'vi> for KeyStrVisitor {
+            type Value = HashKey;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(formatter, "a key of {} hex digits", KEY_LEN * 2)
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<HashKey, E> {
+                value.parse::<HashKey>().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(KeyStrVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::documents::{DocMeta, HasMeta};
+    use crate::testing::pool;
+
+    #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, Entity, HasMeta)]
+    #[entity(prefix = "hashedvoter")]
+    struct Voter {
+        #[serde(flatten)]
+        meta: DocMeta<Voter>,
+    }
+
+    #[derive(Debug, Entity)]
+    #[entity(prefix = "invitation")]
+    struct Invitation;
+
+    const SOME_KEY: &str = "000102030405060708090a0b0c0d0e0f";
+
+    #[test]
+    fn should_be_deterministic_per_key() {
+        let key = SOME_KEY.parse::<HashKey>().expect("key");
+        let a = IdGen::hashed_with::<Voter, _>(&key, "voter@example.com");
+        let b = IdGen::hashed_with::<Voter, _>(&key, "voter@example.com");
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn should_differ_from_unkeyed_hash() {
+        let key = SOME_KEY.parse::<HashKey>().expect("key");
+        let keyed = IdGen::hashed_with::<Voter, _>(&key, "voter@example.com");
+        let unkeyed = Id::<Voter>::hashed("voter@example.com");
+        assert_ne!(keyed.untyped(), unkeyed.untyped());
+    }
+
+    #[test]
+    fn should_differ_between_keys() {
+        let a = IdGen::hashed_with::<Voter, _>(&HashKey::generate(), "voter@example.com");
+        let b = IdGen::hashed_with::<Voter, _>(&HashKey::generate(), "voter@example.com");
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn should_separate_entity_types() {
+        let key = SOME_KEY.parse::<HashKey>().expect("key");
+        let voter = IdGen::hashed_with::<Voter, _>(&key, "voter@example.com");
+        let invitation = IdGen::hashed_with::<Invitation, _>(&key, "voter@example.com");
+        assert_ne!(voter.untyped(), invitation.untyped());
+    }
+
+    #[test]
+    fn should_not_reveal_key_in_debug_output() {
+        let key = SOME_KEY.parse::<HashKey>().expect("key");
+        let debug = format!("{:?}", key);
+        assert!(!debug.contains("0a0b"), "Debug output: {}", debug);
+    }
+
+    #[test]
+    fn should_load_keys_from_config() {
+        let keys: HashKeys = serde_json::from_str(&format!(
+            "{{\"current\": \"{}\", \"previous\": [\"{}\"]}}",
+            SOME_KEY, "ffeeddccbbaa99887766554433221100"
+        ))
+        .expect("parse keys");
+        assert_eq!(keys.current, SOME_KEY.parse().expect("key"));
+        assert_eq!(keys.previous.len(), 1);
+
+        let json = serde_json::to_string(&keys).expect("serialize keys");
+        let keys2: HashKeys = serde_json::from_str(&json).expect("reparse keys");
+        assert_eq!(keys, keys2);
+    }
+
+    #[test]
+    fn should_reject_short_keys() {
+        assert!("0001020304".parse::<HashKey>().is_err());
+    }
+
+    #[test]
+    fn should_find_documents_hashed_with_previous_key() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("hash_keys_should_find_documents_hashed_with_previous_key")?;
+        let docs = pool.get()?;
+
+        let old_key = HashKey::generate();
+        let mut voter = Voter {
+            meta: DocMeta::new_with_id(IdGen::hashed_with(&old_key, "voter@example.com")),
+        };
+        docs.save(&mut voter)?;
+
+        let keys = HashKeys {
+            current: HashKey::generate(),
+            previous: vec![old_key],
+        };
+        assert_ne!(keys.hashed::<Voter, _>("voter@example.com"), voter.meta.id);
+
+        let loaded = docs.load_hashed::<Voter, _>(&keys, "voter@example.com")?;
+        assert_eq!(loaded.map(|v| v.meta.id), Some(voter.meta.id));
+        Ok(())
+    }
+}
@@ -1,15 +1,23 @@
-use failure::{bail, Fail};
+use failure::Fail;
 use std::cmp::Ordering;
 use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 use data_encoding::BASE32_DNSSEC;
-use failure::Error;
+use postgres::types::{FromSql, IsNull, ToSql, Type};
+use postgres::{accepts, to_sql_checked};
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
+use crate::clock::{Clock, ManualClock, SystemClock};
 use crate::untyped_ids::UntypedId;
 
+pub use infra_derive::Entity;
+
 pub(crate) const ENCODED_BARE_ID_LEN: usize = 26;
 
 #[derive(Debug)]
@@ -19,20 +27,59 @@ pub struct Id<T> {
     phantom: PhantomData<T>,
 }
 
-#[derive(Debug, Clone, Fail)]
+/// Why we could not parse an id. Offsets are in bytes, from the start of
+/// the string we were given.
+#[derive(Debug, Clone, PartialEq, Eq, Fail)]
 pub enum IdParseError {
-    InvalidPrefix,
+    /// The id does not start with the prefix of the entity we expected.
+    InvalidPrefix { expected: String, found: String },
+    /// The encoded portion contains a character outside of the alphabet.
+    InvalidChar { found: char, offset: usize },
+    /// The encoded portion has the wrong number of characters.
+    InvalidLength { found: usize, expected: usize },
+    /// The check symbols of a checksummed id do not match. If exactly one
+    /// character was mistyped, `position` is its offset in the string.
+    ChecksumMismatch { position: Option<usize> },
+    /// The id is not in a form that we recognise.
     Unparseable,
+    /// The id's timestamp is later than we can represent, such as in a
+    /// UUID from another system.
+    TimestampOutOfRange,
 }
 
+/// Implement this with `#[derive(Entity)]`, which also adds the prefix to
+/// `crate::registry`, so that we can check that it is unique. Should you
+/// implement it by hand, register the prefix yourself, or
+/// `registry::validate` will not know about it:
+///
+/// ```ignore
+/// infra::inventory::submit! {
+///     #![crate = infra]
+///     infra::registry::EntityRegistration::new("ballot", "my_crate::Ballot")
+/// }
+/// ```
 pub trait Entity {
     const PREFIX: &'static str;
 }
 
-#[derive(Debug, Clone, Default)]
-pub struct IdGen {}
+/// Generates ids that are strictly increasing, even when the clock steps
+/// backwards. Clones share their state, and so also generate ids in order.
+#[derive(Debug, Clone)]
+pub struct IdGen {
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) state: Arc<Mutex<IdGenState>>,
+}
 
-const DIVIDER: &str = ".";
+#[derive(Debug, Default)]
+pub(crate) struct IdGenState {
+    // The stamp of the last id generated.
+    pub(crate) last_stamp: u64,
+    // When seeded, we draw random portions from here rather than the thread
+    // local generator, so that ids are reproducible.
+    pub(crate) rng: Option<ChaCha20Rng>,
+}
+
+pub(crate) const DIVIDER: &str = ".";
 
 impl<T> Id<T> {
     /// Returns a id nominally at time zero, but with a random portion derived
@@ -49,6 +96,23 @@ impl IdGen {
         Default::default()
     }
 
+    /// Returns a generator that reads the time from `clock`, and draws
+    /// random portions from a generator seeded with `seed`.
+    pub fn with_clock<C: Clock + 'static>(clock: C, seed: u64) -> Self {
+        let clock = Arc::new(clock);
+        let state = Arc::new(Mutex::new(IdGenState {
+            last_stamp: 0,
+            rng: Some(ChaCha20Rng::seed_from_u64(seed)),
+        }));
+        IdGen { clock, state }
+    }
+
+    /// Returns a generator whose clock stays at `start_time`, so that given
+    /// the same seed, it always generates the same sequence of ids.
+    pub fn deterministic(seed: u64, start_time: SystemTime) -> Self {
+        Self::with_clock(ManualClock::new(start_time), seed)
+    }
+
     pub fn generate<T>(&self) -> Id<T> {
         let inner = self.untyped();
         let phantom = PhantomData;
@@ -57,15 +121,15 @@ impl IdGen {
     }
 }
 
-impl<T> Id<T> {
-    fn from_bytes(bytes: &[u8]) -> Self {
-        let inner = UntypedId::from_bytes(bytes);
-
-        let phantom = PhantomData;
-
-        Id { inner, phantom }
+impl Default for IdGen {
+    fn default() -> Self {
+        let clock = Arc::new(SystemClock);
+        let state = Arc::new(Mutex::new(IdGenState::default()));
+        IdGen { clock, state }
     }
+}
 
+impl<T> Id<T> {
     fn to_bytes(&self) -> Vec<u8> {
         self.inner.to_bytes()
     }
@@ -99,28 +163,31 @@ impl<T: Entity> fmt::Display for Id<T> {
 }
 
 impl<T: Entity> std::str::FromStr for Id<T> {
-    type Err = Error;
+    type Err = IdParseError;
     fn from_str(src: &str) -> Result<Self, Self::Err> {
-        let expected_length = T::PREFIX.len() + DIVIDER.len();
-        if src.len() < expected_length {
-            bail!(IdParseError::InvalidPrefix);
-        };
-        let (start, remainder) = src.split_at(T::PREFIX.len());
-        if start != T::PREFIX {
-            bail!(IdParseError::InvalidPrefix);
-        }
-        let (divider, b64) = remainder.split_at(DIVIDER.len());
-
-        if divider != DIVIDER {
-            bail!(IdParseError::Unparseable);
-        }
-
-        let mut bytes = [0u8; 16];
-        BASE32_DNSSEC
-            .decode_mut(b64.as_bytes(), &mut bytes)
-            .map_err(|e| failure::format_err!("{:?}", e))?;
+        let offset = encoded_offset(src, T::PREFIX)?;
+        Ok(UntypedId::parse_at(src, offset)?.typed())
+    }
+}
 
-        Ok(Self::from_bytes(&bytes[..]))
+/// Returns where the encoded portion of `src` starts, after `prefix` and
+/// the divider.
+pub(crate) fn encoded_offset(src: &str, prefix: &str) -> Result<usize, IdParseError> {
+    match src.find(DIVIDER) {
+        Some(end) if &src[..end] == prefix => Ok(end + DIVIDER.len()),
+        Some(end) => Err(IdParseError::InvalidPrefix {
+            expected: prefix.to_string(),
+            found: src[..end].to_string(),
+        }),
+        None if src == prefix => Err(IdParseError::InvalidLength {
+            found: 0,
+            expected: ENCODED_BARE_ID_LEN,
+        }),
+        None if src.starts_with(prefix) => Err(IdParseError::invalid_char(src, prefix.len())),
+        None => Err(IdParseError::InvalidPrefix {
+            expected: prefix.to_string(),
+            found: src.to_string(),
+        }),
     }
 }
 
@@ -161,9 +228,14 @@ impl<T> Hash for Id<T> {
     }
 }
 
+// In binary formats, we leave out the prefix, as the type implies it.
 impl<T: Entity> Serialize for Id<T> {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        serializer.serialize_str(&self.to_string())
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            self.inner.serialize(serializer)
+        }
     }
 }
 
@@ -182,15 +254,79 @@ impl<'de, T: Entity> Deserialize<'de> for Id<T> {
             }
         }
 
-        deserializer.deserialize_str(IdStrVisitor(PhantomData))
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(IdStrVisitor(PhantomData))
+        } else {
+            UntypedId::deserialize(deserializer).map(|id| id.typed())
+        }
+    }
+}
+
+impl<T: Entity + fmt::Debug> ToSql for Id<T> {
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut Vec<u8>,
+    ) -> Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        self.to_string().to_sql(ty, out)
+    }
+
+    accepts!(postgres::types::TEXT, postgres::types::VARCHAR);
+
+    to_sql_checked!();
+}
+
+impl<T: Entity> FromSql for Id<T> {
+    fn from_sql(
+        ty: &Type,
+        raw: &[u8],
+    ) -> Result<Self, Box<dyn std::error::Error + 'static + Send + Sync>> {
+        let s = String::from_sql(ty, raw)?;
+        let id = s.parse::<Id<T>>().map_err(|e| e.compat())?;
+        Ok(id)
+    }
+
+    accepts!(postgres::types::TEXT, postgres::types::VARCHAR);
+}
+
+impl IdParseError {
+    pub(crate) fn invalid_char(src: &str, offset: usize) -> Self {
+        let found = src
+            .get(offset..)
+            .and_then(|s| s.chars().next())
+            .unwrap_or(std::char::REPLACEMENT_CHARACTER);
+        IdParseError::InvalidChar { found, offset }
     }
 }
 
 impl fmt::Display for IdParseError {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            IdParseError::InvalidPrefix => write!(fmt, "Invalid prefix"),
+            IdParseError::InvalidPrefix { expected, found } => write!(
+                fmt,
+                "Invalid prefix; expected {:?}, found {:?}",
+                expected, found
+            ),
+            IdParseError::InvalidChar { found, offset } => {
+                write!(fmt, "Invalid character {:?} at offset {}", found, offset)
+            }
+            IdParseError::InvalidLength { found, expected } => write!(
+                fmt,
+                "Invalid length; expected {} characters, found {}",
+                expected, found
+            ),
             IdParseError::Unparseable => write!(fmt, "Unparseable Id"),
+            IdParseError::TimestampOutOfRange => write!(fmt, "Timestamp out of range"),
+            IdParseError::ChecksumMismatch {
+                position: Some(position),
+            } => write!(
+                fmt,
+                "Checksum mismatch; likely a typo at offset {}",
+                position
+            ),
+            IdParseError::ChecksumMismatch { position: None } => {
+                write!(fmt, "Checksum mismatch")
+            }
         }
     }
 }
@@ -200,13 +336,10 @@ mod test {
     use super::*;
     use serde_json;
 
-    #[derive(Debug)]
+    #[derive(Debug, Entity)]
+    #[entity(prefix = "canary")]
     struct Canary;
 
-    impl Entity for Canary {
-        const PREFIX: &'static str = "canary";
-    }
-
     #[test]
     fn round_trips_via_to_from_str() {
         let id = Id::<Canary>::hashed(&"Hi!");
@@ -235,6 +368,37 @@ mod test {
         assert_eq!(id, id2);
     }
 
+    #[test]
+    fn round_trips_via_bincode() {
+        let id = Id::<Canary>::hashed("boo");
+
+        let bytes = bincode::serialize(&id).expect("bincode::serialize");
+        let id2: Id<Canary> = bincode::deserialize(&bytes).expect("bincode::deserialize");
+        assert_eq!(id, id2);
+    }
+
+    #[test]
+    fn round_trips_via_cbor_within_struct() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Archived {
+            id: Id<Canary>,
+            votes: u32,
+        }
+        let archived = Archived {
+            id: IdGen::new().generate(),
+            votes: 42,
+        };
+
+        let bytes = serde_cbor::to_vec(&archived).expect("serde_cbor::to_vec");
+        assert!(
+            !bytes.windows(6).any(|w| w == b"canary"),
+            "Binary form should omit prefix: {:?}",
+            bytes
+        );
+        let archived2: Archived = serde_cbor::from_slice(&bytes).expect("serde_cbor::from_slice");
+        assert_eq!(archived, archived2);
+    }
+
     #[test]
     fn round_trips_via_untyped() {
         let id = Id::<Canary>::hashed(&"boo");
@@ -320,13 +484,11 @@ mod test {
 
     #[test]
     fn should_yield_useful_error_when_invalid_prefix() {
-        #[derive(Debug)]
+        // Borrowed from https://en.wikipedia.org/wiki/Longest_word_in_English
+        // We want it to be longer than the id string in total.
+        #[derive(Debug, Entity)]
+        #[entity(prefix = "pseudopseudohypoparathyroidism")]
         struct Long;
-        impl Entity for Long {
-            // Borrowed from https://en.wikipedia.org/wiki/Longest_word_in_English
-            // We want it to be longer than the id string in total.
-            const PREFIX: &'static str = "pseudopseudohypoparathyroidism";
-        }
         let s = "wrong-0000000000001q5nnvfqq7krfo";
 
         let result = s.parse::<Id<Long>>();
@@ -363,4 +525,68 @@ mod test {
             result,
         )
     }
+
+    #[test]
+    fn should_report_expected_and_found_prefix() {
+        let result = "ballot.0000000000001q5nnvfqq7krfo".parse::<Id<Canary>>();
+        assert_eq!(
+            result,
+            Err(IdParseError::InvalidPrefix {
+                expected: "canary".to_string(),
+                found: "ballot".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn should_report_prefix_that_continues_past_ours() {
+        let result = "canaryish.0000000000001q5nnvfqq7krfo".parse::<Id<Canary>>();
+        assert_eq!(
+            result,
+            Err(IdParseError::InvalidPrefix {
+                expected: "canary".to_string(),
+                found: "canaryish".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn should_report_offset_of_bad_character() {
+        let result = "canary.0000000000001q5nnvfqz7krfo".parse::<Id<Canary>>();
+        assert_eq!(
+            result,
+            Err(IdParseError::InvalidChar {
+                found: 'z',
+                offset: 27
+            })
+        );
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Invalid character 'z' at offset 27"
+        );
+    }
+
+    #[test]
+    fn should_report_wrong_divider_as_bad_character() {
+        let result = "canary#0000000000001q5nnvfqq7krfo".parse::<Id<Canary>>();
+        assert_eq!(
+            result,
+            Err(IdParseError::InvalidChar {
+                found: '#',
+                offset: 6
+            })
+        );
+    }
+
+    #[test]
+    fn should_report_length_of_encoded_portion() {
+        let result = "canary.0000000000001q5nnvfqq7kr".parse::<Id<Canary>>();
+        assert_eq!(
+            result,
+            Err(IdParseError::InvalidLength {
+                found: 24,
+                expected: ENCODED_BARE_ID_LEN
+            })
+        );
+    }
 }
@@ -0,0 +1,279 @@
+use uuid::Uuid;
+
+use crate::ids::IdParseError;
+use crate::untyped_ids::UntypedId;
+
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const ULID_LEN: usize = 26;
+const NANOS_PER_MILLI: u64 = 1000 * 1000;
+// UUIDv7 keeps a 48 bit millisecond timestamp, and we use the 12 bits of
+// `rand_a` for the fraction of the millisecond.
+const V7_FRACTION_STEPS: u64 = 1 << 12;
+const V7_MAX_MILLIS: u64 = (1 << 48) - 1;
+
+/// Converts losslessly to a UUID with the same 16 bytes. Note that the
+/// version and variant fields will be whatever our id happens to contain;
+/// see `approximate_uuid_v7` for a layout that other systems will recognise.
+impl From<UntypedId> for Uuid {
+    fn from(id: UntypedId) -> Self {
+        Uuid::from_u128(id.to_u128())
+    }
+}
+
+impl From<Uuid> for UntypedId {
+    fn from(uuid: Uuid) -> Self {
+        UntypedId::from_u128(uuid.as_u128())
+    }
+}
+
+impl UntypedId {
+    fn to_u128(self) -> u128 {
+        (u128::from(self.stamp) << 64) | u128::from(self.random)
+    }
+
+    fn from_u128(val: u128) -> Self {
+        let stamp = (val >> 64) as u64;
+        let random = val as u64;
+        UntypedId { stamp, random }
+    }
+
+    /// Converts to a version 7 UUID, which carries a millisecond timestamp
+    /// that other systems can sort by. This is lossy, as the version and
+    /// variant take up six of the 128 bits: we keep the timestamp to within
+    /// 1/4096 of a millisecond, and the top 62 bits of the random portion.
+    /// Use `Uuid::from` or `to_ulid` where we need the id back exactly.
+    pub fn approximate_uuid_v7(&self) -> Uuid {
+        let millis = std::cmp::min(self.stamp / NANOS_PER_MILLI, V7_MAX_MILLIS);
+        let fraction = (self.stamp % NANOS_PER_MILLI) * V7_FRACTION_STEPS / NANOS_PER_MILLI;
+
+        let val = (u128::from(millis) << 80)
+            | (0x7 << 76)
+            | (u128::from(fraction) << 64)
+            | (0b10 << 62)
+            | u128::from(self.random >> 2);
+        Uuid::from_u128(val)
+    }
+
+    /// Converts from a version 7 UUID, such as from `approximate_uuid_v7`.
+    /// UUIDs from other systems may have timestamps later than we can
+    /// represent, in around the year 2554.
+    pub fn from_uuid_v7(uuid: &Uuid) -> Result<Self, IdParseError> {
+        if uuid.get_version_num() != 7 {
+            return Err(IdParseError::Unparseable);
+        }
+        let val = uuid.as_u128();
+        let millis = (val >> 80) as u64;
+        let fraction = ((val >> 64) & 0xfff) as u64;
+        let rand_b = val as u64 & ((1 << 62) - 1);
+
+        // Round up, so that converting back yields the same fraction.
+        let nanos = (fraction * NANOS_PER_MILLI).div_ceil(V7_FRACTION_STEPS);
+        let stamp = match millis
+            .checked_mul(NANOS_PER_MILLI)
+            .and_then(|stamp| stamp.checked_add(nanos))
+        {
+            Some(stamp) => stamp,
+            None => return Err(IdParseError::TimestampOutOfRange),
+        };
+        let random = rand_b << 2;
+        Ok(UntypedId { stamp, random })
+    }
+
+    /// Encodes the id's 128 bits as a ULID string. This is lossless, but
+    /// as our timestamp is in nanoseconds, the ULID's 48 bit "millisecond"
+    /// field will not be meaningful to other systems.
+    pub fn to_ulid(&self) -> String {
+        let val = self.to_u128();
+        (0..ULID_LEN)
+            .map(|i| {
+                let shift = 5 * (ULID_LEN - 1 - i);
+                CROCKFORD[((val >> shift) & 0x1f) as usize] as char
+            })
+            .collect()
+    }
+
+    /// Decodes a ULID string, accepting lower case, and the letters that
+    /// Crockford's base32 treats as aliases.
+    pub fn from_ulid(src: &str) -> Result<Self, IdParseError> {
+        if src.len() != ULID_LEN {
+            return Err(IdParseError::InvalidLength {
+                found: src.len(),
+                expected: ULID_LEN,
+            });
+        }
+        let mut val: u128 = 0;
+        for (i, c) in src.char_indices() {
+            let digit = match crockford_digit(c) {
+                Some(digit) => digit,
+                None => return Err(IdParseError::invalid_char(src, i)),
+            };
+            // The first character only has room for three bits.
+            if i == 0 && digit > 7 {
+                return Err(IdParseError::invalid_char(src, i));
+            }
+            val = (val << 5) | u128::from(digit);
+        }
+        Ok(UntypedId::from_u128(val))
+    }
+}
+
+fn crockford_digit(c: char) -> Option<u8> {
+    let c = match c.to_ascii_uppercase() {
+        'O' => '0',
+        'I' | 'L' => '1',
+        c => c,
+    };
+    CROCKFORD
+        .iter()
+        .position(|&d| d as char == c)
+        .map(|p| p as u8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ids::IdGen;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn round_trips_via_uuid() {
+        let id = IdGen::new().untyped();
+        let uuid = Uuid::from(id);
+        assert_eq!(UntypedId::from(uuid), id);
+    }
+
+    #[test]
+    fn uuid_should_have_same_bytes() {
+        let id = "0000000000001q5nnvfqq7krfo"
+            .parse::<UntypedId>()
+            .expect("parse");
+        let uuid = Uuid::from(id);
+        assert_eq!(uuid.to_string(), "00000000-0000-0000-e8b7-bfdfad1e9b7e");
+    }
+
+    #[test]
+    fn uuid_v7_should_be_recognisable() {
+        let id = IdGen::new().untyped();
+        let uuid = id.approximate_uuid_v7();
+        assert_eq!(uuid.get_version_num(), 7);
+        assert_eq!(uuid.get_variant(), Some(uuid::Variant::RFC4122));
+    }
+
+    #[test]
+    fn uuid_v7_should_carry_unix_millis() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_millis(1_500_000_000_123);
+        let id = IdGen::deterministic(0, start).untyped();
+        let uuid = id.approximate_uuid_v7();
+        let millis = (uuid.as_u128() >> 80) as u64;
+        assert_eq!(millis, 1_500_000_000_123);
+    }
+
+    #[test]
+    fn round_trips_approximately_via_uuid_v7() {
+        let id = IdGen::new().untyped();
+        let back = UntypedId::from_uuid_v7(&id.approximate_uuid_v7()).expect("from v7");
+
+        let step = NANOS_PER_MILLI / V7_FRACTION_STEPS + 1;
+        assert!(
+            back.stamp + step > id.stamp && back.stamp < id.stamp + step,
+            "{:?} ~= {:?}",
+            back,
+            id
+        );
+        assert_eq!(back.random, id.random & !0b11);
+        assert_eq!(back.approximate_uuid_v7(), id.approximate_uuid_v7());
+    }
+
+    #[test]
+    fn uuid_v7_should_preserve_order() {
+        let idgen = IdGen::new();
+        let a = idgen.untyped();
+        std::thread::sleep(Duration::from_millis(1));
+        let b = idgen.untyped();
+
+        assert!(a.approximate_uuid_v7() < b.approximate_uuid_v7());
+    }
+
+    #[test]
+    fn should_reject_other_uuid_versions_as_v7() {
+        let uuid =
+            Uuid::from_u128(IdGen::new().untyped().approximate_uuid_v7().as_u128() & !(0xf << 76));
+        assert!(UntypedId::from_uuid_v7(&uuid).is_err());
+    }
+
+    #[test]
+    fn should_reject_uuid_v7_beyond_our_range() {
+        let max_millis = u64::MAX / NANOS_PER_MILLI;
+        for &(millis, fraction) in &[(max_millis + 1, 0), (max_millis, 0xfff), (V7_MAX_MILLIS, 0)] {
+            let uuid = Uuid::from_u128(
+                (u128::from(millis) << 80) | (0x7 << 76) | (fraction << 64) | (0b10 << 62),
+            );
+            assert_eq!(
+                UntypedId::from_uuid_v7(&uuid),
+                Err(IdParseError::TimestampOutOfRange),
+                "uuid: {}",
+                uuid
+            );
+        }
+
+        let uuid = Uuid::from_u128((u128::from(max_millis) << 80) | (0x7 << 76) | (0b10 << 62));
+        let id = UntypedId::from_uuid_v7(&uuid).expect("latest v7 that fits");
+        assert_eq!(id.stamp, max_millis * NANOS_PER_MILLI);
+    }
+
+    #[test]
+    fn round_trips_via_ulid() {
+        let id = IdGen::new().untyped();
+        let ulid = id.to_ulid();
+        assert_eq!(ulid.len(), ULID_LEN);
+        assert_eq!(UntypedId::from_ulid(&ulid).expect("from ulid"), id);
+        assert_eq!(
+            UntypedId::from_ulid(&ulid.to_lowercase()).expect("from lower case ulid"),
+            id
+        );
+    }
+
+    #[test]
+    fn ulid_should_preserve_order() {
+        let idgen = IdGen::new();
+        let a = idgen.untyped();
+        let b = idgen.untyped();
+
+        assert!(a.to_ulid() < b.to_ulid());
+    }
+
+    #[test]
+    fn should_decode_known_ulid() {
+        let id = UntypedId::from_ulid("7ZZZZZZZZZZZZZZZZZZZZZZZZZ").expect("max ulid");
+        assert_eq!(id.stamp, u64::MAX);
+        assert_eq!(id.random, u64::MAX);
+
+        let id = UntypedId::from_ulid("0000000000000000000000000o").expect("aliased ulid");
+        assert_eq!((id.stamp, id.random), (0, 0));
+    }
+
+    #[test]
+    fn should_reject_invalid_ulids() {
+        for s in &[
+            "8ZZZZZZZZZZZZZZZZZZZZZZZZZ",
+            "0000000000000000000000000",
+            "000000000000000000000000000",
+            "0000000000000000000000000U",
+        ] {
+            let result = UntypedId::from_ulid(s);
+            assert!(
+                result.is_err(),
+                "Parsing {:?} should return error; got {:?}",
+                s,
+                result,
+            )
+        }
+        assert_eq!(
+            UntypedId::from_ulid("0000000000000000000000000U"),
+            Err(IdParseError::InvalidChar {
+                found: 'U',
+                offset: 25
+            })
+        );
+    }
+}
@@ -1,4 +1,24 @@
+// Lets the derives in `infra_derive` refer to `::infra` from within this crate.
+extern crate self as infra;
+
+pub mod any_ids;
+pub mod checksum;
+pub mod clock;
 pub mod documents;
+pub mod events;
+pub mod hash_keys;
 pub mod ids;
+pub mod interop;
+pub mod outbox;
 pub mod persistence;
+pub mod projections;
+pub mod public_ids;
+pub mod registry;
+pub mod sagas;
 pub mod untyped_ids;
+
+#[cfg(test)]
+mod testing;
+
+#[doc(hidden)]
+pub use inventory;
@@ -0,0 +1,531 @@
+use std::time::{Duration, SystemTime};
+
+use failure::{Error, Fail};
+use log::*;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::documents::{Envelope, HasMailBox, HasMeta};
+use crate::ids::{Entity, Id};
+use crate::persistence::{Documents, Jsonb};
+use crate::untyped_ids::UntypedId;
+
+/// Describes how often we attempt to deliver a message before giving up on
+/// it, and how long we wait between attempts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+/// The result of an attempt to deliver the next outgoing message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Delivery {
+    /// There were no messages ready for delivery.
+    Idle,
+    Delivered(UntypedId),
+    /// The handler failed, and we will try again once `backoff` has elapsed.
+    Retrying {
+        message_id: UntypedId,
+        attempts: u32,
+        backoff: Duration,
+    },
+    /// The handler failed too many times, so the message has been removed
+    /// from the outbox and recorded as a dead letter.
+    DeadLettered(UntypedId),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeadLetter {
+    pub message_id: UntypedId,
+    pub document_id: String,
+    pub envelope: serde_json::Value,
+    pub attempts: u32,
+    pub last_error: String,
+    pub failed_at: SystemTime,
+}
+
+#[derive(Fail, Debug, PartialEq, Eq)]
+#[fail(display = "no such dead letter: {}", _0)]
+pub struct NoSuchDeadLetter(pub UntypedId);
+
+#[derive(Fail, Debug, PartialEq, Eq)]
+#[fail(display = "missing document: {}", _0)]
+pub struct MissingDocument(pub String);
+
+const LOAD_ATTEMPTS_SQL: &str = "SELECT attempts FROM outbox_attempts
+                                    WHERE message_id = $1
+                                    FOR UPDATE";
+const RECORD_ATTEMPT_SQL: &str = "INSERT INTO outbox_attempts
+                                    (message_id, document_id, attempts, next_attempt_at, last_error)
+                                    VALUES ($1, $2, $3, now() + $4::float8 * interval '1 second', $5)
+                                    ON CONFLICT (message_id) DO UPDATE
+                                        SET attempts = EXCLUDED.attempts,
+                                            next_attempt_at = EXCLUDED.next_attempt_at,
+                                            last_error = EXCLUDED.last_error";
+const CLEAR_ATTEMPTS_SQL: &str = "DELETE FROM outbox_attempts WHERE message_id = $1";
+const INSERT_DEAD_LETTER_SQL: &str = "INSERT INTO dead_letters
+                                    (message_id, document_id, envelope, attempts, last_error)
+                                    VALUES ($1, $2, $3, $4, $5)";
+const LIST_DEAD_LETTERS_SQL: &str = "SELECT message_id, document_id, envelope, attempts,
+                                        last_error,
+                                        (extract(epoch FROM failed_at) * 1000000)::int8
+                                    FROM dead_letters
+                                    ORDER BY failed_at, message_id";
+const LOAD_DEAD_LETTER_SQL: &str = "SELECT document_id, envelope FROM dead_letters
+                                    WHERE message_id = $1";
+const DELETE_DEAD_LETTER_SQL: &str = "DELETE FROM dead_letters WHERE message_id = $1";
+
+impl RetryPolicy {
+    /// Returns how long to wait after the given number of failed attempts.
+    /// This doubles with each attempt, up to `max_backoff`.
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        let exponent = attempts.saturating_sub(1).min(31);
+        self.initial_backoff
+            .checked_mul(1 << exponent)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 8,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(300),
+        }
+    }
+}
+
+impl Documents {
+    /// Passes the first outgoing message of some document of type `D` to
+    /// `handler`. If the handler succeeds, the message is removed from the
+    /// document's outbox. Otherwise, we record the failure and leave the
+    /// document alone until the backoff given by `policy` has elapsed, or
+    /// move the message to the dead letters once it has had `max_attempts`.
+    pub fn deliver_next<D, A, F>(&self, policy: &RetryPolicy, handler: F) -> Result<Delivery, Error>
+    where
+        D: Serialize + DeserializeOwned + Entity + HasMeta<D> + HasMailBox<A>,
+        A: Serialize,
+        F: FnOnce(&Envelope<A>) -> Result<(), Error>,
+    {
+        let mut document = match self.load_next_unsent::<D>()? {
+            Some(document) => document,
+            None => return Ok(Delivery::Idle),
+        };
+        let envelope = match document.mailbox_mut().pop_front() {
+            Some(envelope) => envelope,
+            None => return Ok(Delivery::Idle),
+        };
+        let document_id = document.meta().id.to_string();
+        let message_id = envelope.id.to_string();
+
+        let outcome = handler(&envelope);
+
+        let t = self.connection.transaction()?;
+        let delivery = match outcome {
+            Ok(()) => {
+                debug!("Delivered {} from {}", message_id, document_id);
+                self.save_in(&t, &mut document)?;
+                t.prepare_cached(CLEAR_ATTEMPTS_SQL)?
+                    .execute(&[&message_id])?;
+                Delivery::Delivered(envelope.id)
+            }
+            Err(e) => {
+                warn!(
+                    "Delivery of {} from {} failed: {}",
+                    message_id, document_id, e
+                );
+                let last_error = e.to_string();
+                let previous = t
+                    .prepare_cached(LOAD_ATTEMPTS_SQL)?
+                    .query(&[&message_id])?
+                    .iter()
+                    .next()
+                    .map(|row| row.get::<_, i32>(0))
+                    .unwrap_or(0);
+                let attempts = previous as u32 + 1;
+
+                if attempts >= policy.max_attempts {
+                    self.save_in(&t, &mut document)?;
+                    t.prepare_cached(INSERT_DEAD_LETTER_SQL)?.execute(&[
+                        &message_id,
+                        &document_id,
+                        &Jsonb(&envelope),
+                        &(attempts as i32),
+                        &last_error,
+                    ])?;
+                    t.prepare_cached(CLEAR_ATTEMPTS_SQL)?
+                        .execute(&[&message_id])?;
+                    Delivery::DeadLettered(envelope.id)
+                } else {
+                    let backoff = policy.backoff(attempts);
+                    t.prepare_cached(RECORD_ATTEMPT_SQL)?.execute(&[
+                        &message_id,
+                        &document_id,
+                        &(attempts as i32),
+                        &backoff.as_secs_f64(),
+                        &last_error,
+                    ])?;
+                    Delivery::Retrying {
+                        message_id: envelope.id,
+                        attempts,
+                        backoff,
+                    }
+                }
+            }
+        };
+        t.commit()?;
+
+        Ok(delivery)
+    }
+
+    /// Lists messages that have been given up on, oldest first.
+    pub fn dead_letters(&self) -> Result<Vec<DeadLetter>, Error> {
+        let list = self.connection.prepare_cached(LIST_DEAD_LETTERS_SQL)?;
+        let mut result = Vec::new();
+        for row in list.query(&[])?.iter() {
+            let message_id = row.get::<_, String>(0).parse()?;
+            let document_id = row.get(1);
+            let envelope = row.get(2);
+            let attempts = row.get::<_, i32>(3) as u32;
+            let last_error = row.get(4);
+            let failed_at =
+                SystemTime::UNIX_EPOCH + Duration::from_micros(row.get::<_, i64>(5) as u64);
+            result.push(DeadLetter {
+                message_id,
+                document_id,
+                envelope,
+                attempts,
+                last_error,
+                failed_at,
+            })
+        }
+
+        Ok(result)
+    }
+
+    /// Puts a dead letter back in its document's outbox, ahead of anything
+    /// the document sent after it, so that it will be delivered again.
+    pub fn retry_dead_letter<D, A>(&self, message_id: UntypedId) -> Result<(), Error>
+    where
+        D: Serialize + DeserializeOwned + Entity + HasMeta<D> + HasMailBox<A>,
+        A: Serialize + DeserializeOwned,
+    {
+        let (document_id, envelope) = {
+            let load = self.connection.prepare_cached(LOAD_DEAD_LETTER_SQL)?;
+            let rows = load.query(&[&message_id.to_string()])?;
+            let row = rows.iter().next().ok_or(NoSuchDeadLetter(message_id))?;
+            let document_id: String = row.get(0);
+            let Jsonb(envelope): Jsonb<Envelope<A>> = row.get(1);
+            (document_id, envelope)
+        };
+
+        let id = document_id.parse::<Id<D>>()?;
+        let mut document = self.load(&id)?.ok_or(MissingDocument(document_id))?;
+        document.mailbox_mut().requeue(envelope);
+
+        let t = self.connection.transaction()?;
+        self.save_in(&t, &mut document)?;
+        let rows = t
+            .prepare_cached(DELETE_DEAD_LETTER_SQL)?
+            .execute(&[&message_id.to_string()])?;
+        if rows == 0 {
+            return Err(NoSuchDeadLetter(message_id).into());
+        }
+        t.commit()?;
+
+        Ok(())
+    }
+
+    /// Forgets about a dead letter entirely.
+    pub fn discard_dead_letter(&self, message_id: UntypedId) -> Result<(), Error> {
+        let rows = self
+            .connection
+            .prepare_cached(DELETE_DEAD_LETTER_SQL)?
+            .execute(&[&message_id.to_string()])?;
+        if rows == 0 {
+            return Err(NoSuchDeadLetter(message_id).into());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::documents::*;
+    use crate::testing::{pool, IDGEN};
+    use failure::bail;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Clone, Debug, Deserialize, Serialize, Entity, HasMeta)]
+    #[entity(prefix = "sender")]
+    struct Sender {
+        #[serde(flatten)]
+        meta: DocMeta<Sender>,
+        #[serde(flatten)]
+        mbox: MailBox<String>,
+    }
+
+    impl HasMailBox<String> for Sender {
+        fn mailbox(&self) -> &MailBox<String> {
+            &self.mbox
+        }
+        fn mailbox_mut(&mut self) -> &mut MailBox<String> {
+            &mut self.mbox
+        }
+    }
+
+    fn sender_with(messages: &[&str]) -> Sender {
+        let mut sender = Sender {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            mbox: MailBox::default(),
+        };
+        for msg in messages {
+            sender.mbox.send(&IDGEN, msg.to_string());
+        }
+        sender
+    }
+
+    fn failing(_: &Envelope<String>) -> Result<(), Error> {
+        bail!("Computer says no")
+    }
+
+    fn no_backoff(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_secs(0),
+            max_backoff: Duration::from_secs(0),
+        }
+    }
+
+    #[test]
+    fn backoff_should_double_until_max() {
+        let policy = RetryPolicy {
+            max_attempts: 100,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+        };
+
+        let backoffs = (1..=6).map(|n| policy.backoff(n)).collect::<Vec<_>>();
+
+        assert_eq!(
+            backoffs,
+            [1, 2, 4, 8, 10, 10]
+                .iter()
+                .cloned()
+                .map(Duration::from_secs)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(policy.backoff(99), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn should_deliver_in_send_order() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("outbox_should_deliver_in_send_order")?;
+        let docs = pool.get()?;
+
+        docs.save(&mut sender_with(&["one", "two", "three"]))?;
+
+        let mut received = Vec::new();
+        loop {
+            let delivery = docs.deliver_next::<Sender, _, _>(&RetryPolicy::default(), |e| {
+                received.push(e.message.clone());
+                Ok(())
+            })?;
+            debug!("Delivery: {:?}", delivery);
+            if delivery == Delivery::Idle {
+                break;
+            }
+        }
+
+        assert_eq!(received, vec!["one", "two", "three"]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_back_off_after_failure() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("outbox_should_back_off_after_failure")?;
+        let docs = pool.get()?;
+
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_secs(3600),
+            max_backoff: Duration::from_secs(3600),
+            ..RetryPolicy::default()
+        };
+        let mut sender = sender_with(&["one"]);
+        docs.save(&mut sender)?;
+        let message_id = sender.mbox.outgoing().next().expect("message").id;
+
+        let delivery = docs.deliver_next::<Sender, _, _>(&policy, failing)?;
+        assert_eq!(
+            delivery,
+            Delivery::Retrying {
+                message_id,
+                attempts: 1,
+                backoff: Duration::from_secs(3600)
+            }
+        );
+
+        let delivery = docs.deliver_next::<Sender, _, _>(&policy, failing)?;
+        assert_eq!(delivery, Delivery::Idle);
+        Ok(())
+    }
+
+    #[test]
+    fn should_deliver_from_others_while_backing_off() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("outbox_should_deliver_from_others_while_backing_off")?;
+        let docs = pool.get()?;
+
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_secs(3600),
+            ..RetryPolicy::default()
+        };
+        docs.save(&mut sender_with(&["bad"]))?;
+        docs.save(&mut sender_with(&["good"]))?;
+
+        let mut received = Vec::new();
+        for _ in 0..4 {
+            docs.deliver_next::<Sender, _, _>(&policy, |e| {
+                if e.message == "bad" {
+                    bail!("Bad message");
+                }
+                received.push(e.message.clone());
+                Ok(())
+            })?;
+        }
+
+        assert_eq!(received, vec!["good"]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_dead_letter_after_max_attempts() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("outbox_should_dead_letter_after_max_attempts")?;
+        let docs = pool.get()?;
+
+        let policy = no_backoff(2);
+        let mut sender = sender_with(&["one"]);
+        docs.save(&mut sender)?;
+        let message_id = sender.mbox.outgoing().next().expect("message").id;
+
+        let first = docs.deliver_next::<Sender, _, _>(&policy, failing)?;
+        let second = docs.deliver_next::<Sender, _, _>(&policy, failing)?;
+        let third = docs.deliver_next::<Sender, _, _>(&policy, failing)?;
+
+        assert_eq!(
+            (first, second, third),
+            (
+                Delivery::Retrying {
+                    message_id,
+                    attempts: 1,
+                    backoff: Duration::from_secs(0)
+                },
+                Delivery::DeadLettered(message_id),
+                Delivery::Idle
+            )
+        );
+
+        let dead = docs.dead_letters()?;
+        info!("Dead letters: {:?}", dead);
+        assert_eq!(
+            dead.iter()
+                .map(|d| (d.message_id, d.attempts, &*d.last_error))
+                .collect::<Vec<_>>(),
+            vec![(message_id, 2, "Computer says no")]
+        );
+        assert_eq!(dead[0].document_id, sender.meta.id.to_string());
+
+        let loaded = docs.load(&sender.meta.id)?.expect("sender");
+        assert_eq!(loaded.mbox.outgoing().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn should_redeliver_retried_dead_letter() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("outbox_should_redeliver_retried_dead_letter")?;
+        let docs = pool.get()?;
+
+        let policy = no_backoff(1);
+        docs.save(&mut sender_with(&["one"]))?;
+        let message_id = match docs.deliver_next::<Sender, _, _>(&policy, failing)? {
+            Delivery::DeadLettered(id) => id,
+            other => panic!("Expected dead letter, got: {:?}", other),
+        };
+
+        docs.retry_dead_letter::<Sender, String>(message_id)?;
+
+        let mut received = Vec::new();
+        let delivery = docs.deliver_next::<Sender, _, _>(&policy, |e| {
+            received.push(e.id);
+            Ok(())
+        })?;
+
+        assert_eq!(delivery, Delivery::Delivered(message_id));
+        assert_eq!(received, vec![message_id]);
+        assert_eq!(docs.dead_letters()?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_redeliver_retried_dead_letter_before_later_messages() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("outbox_retry_before_later")?;
+        let docs = pool.get()?;
+
+        let policy = no_backoff(1);
+        docs.save(&mut sender_with(&["one", "two", "three"]))?;
+        let message_id = match docs.deliver_next::<Sender, _, _>(&policy, failing)? {
+            Delivery::DeadLettered(id) => id,
+            other => panic!("Expected dead letter, got: {:?}", other),
+        };
+
+        docs.retry_dead_letter::<Sender, String>(message_id)?;
+
+        let mut received = Vec::new();
+        while docs.deliver_next::<Sender, _, _>(&policy, |e| {
+            received.push(e.message.clone());
+            Ok(())
+        })? != Delivery::Idle
+        {}
+
+        assert_eq!(received, vec!["one", "two", "three"]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_forget_discarded_dead_letter() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("outbox_should_forget_discarded_dead_letter")?;
+        let docs = pool.get()?;
+
+        let policy = no_backoff(1);
+        docs.save(&mut sender_with(&["one"]))?;
+        let message_id = match docs.deliver_next::<Sender, _, _>(&policy, failing)? {
+            Delivery::DeadLettered(id) => id,
+            other => panic!("Expected dead letter, got: {:?}", other),
+        };
+
+        docs.discard_dead_letter(message_id)?;
+
+        assert_eq!(docs.dead_letters()?, vec![]);
+        let err = docs
+            .retry_dead_letter::<Sender, String>(message_id)
+            .expect_err("retry should fail");
+        assert_eq!(
+            err.find_root_cause().downcast_ref::<NoSuchDeadLetter>(),
+            Some(&NoSuchDeadLetter(message_id)),
+            "Error: {:?}",
+            err
+        );
+        Ok(())
+    }
+}
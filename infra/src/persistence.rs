@@ -1,41 +1,82 @@
+use std::cell::Cell;
 use std::fmt;
+use std::time::SystemTime;
 
 use failure::Error;
 use failure::Fail;
 use log::*;
+use postgres::transaction::Transaction;
 use postgres::types::{FromSql, IsNull, ToSql, Type};
 use postgres::{accepts, to_sql_checked};
 use r2d2_postgres::PostgresConnectionManager;
 use serde::{de::DeserializeOwned, Serialize};
 use serde_json;
 
-use crate::documents::{HasMeta, Version};
+use crate::documents::{Envelope, HasMeta, Version};
 use crate::ids::{Entity, Id};
+use crate::untyped_ids::UntypedId;
 
 pub trait Storage {
     fn load<D: DeserializeOwned + Entity>(&self, id: &Id<D>) -> Result<Option<D>, Error>;
     fn save<D: Serialize + Entity + HasMeta<D>>(&self, document: &mut D) -> Result<(), Error>;
+    fn has_processed<D: Entity, A>(
+        &self,
+        id: &Id<D>,
+        envelope: &Envelope<A>,
+    ) -> Result<bool, Error>;
+    fn save_processed<D: Serialize + Entity + HasMeta<D>, A>(
+        &self,
+        document: &mut D,
+        envelope: &Envelope<A>,
+    ) -> Result<(), Error>;
 }
 
 #[derive(Fail, Debug, PartialEq, Eq)]
 #[fail(display = "stale version")]
 pub struct ConcurrencyError;
 
+#[derive(Fail, Debug, PartialEq, Eq)]
+#[fail(display = "message already processed")]
+pub struct AlreadyProcessed;
+
 pub struct Documents {
-    connection: postgres::Connection,
+    pub(crate) connection: postgres::Connection,
+    // Whether `setup_binary_keys` has been applied, which we find out on
+    // first use, as the schema may change after we connect.
+    binary_keys: Cell<Option<bool>>,
 }
 
 #[derive(Debug)]
 pub struct DocumentConnectionManager(PostgresConnectionManager);
 
-struct Jsonb<T>(T);
+pub(crate) struct Jsonb<T>(pub(crate) T);
 
 const SETUP_SQL: &str = include_str!("persistence.sql");
+const BINARY_KEYS_SQL: &str = include_str!("binary_keys.sql");
+const HAS_BINARY_KEYS_SQL: &str = "SELECT EXISTS (
+                                        SELECT 1 FROM _migrations
+                                        WHERE id LIKE 'binary-keys 0001 %'
+                                    )";
 const LOAD_SQL: &str = "SELECT body FROM documents WHERE id = $1";
-#[cfg(test)]
+const LOAD_BY_KEY_SQL: &str = "SELECT body FROM documents WHERE kind = $1 AND key = $2";
+// Uses the index on id_key, which sorts in the same order as the ids.
+const LOAD_CREATED_SQL: &str = "SELECT body FROM documents
+                                    WHERE split_part(id, '.', 1) = $1
+                                    AND id_key(id) >= $2 AND id_key(id) < $3
+                                    ORDER BY id_key(id)";
+const LOAD_CREATED_BY_KEY_SQL: &str = "SELECT body FROM documents
+                                    WHERE kind = $1
+                                    AND key >= $2 AND key < $3
+                                    ORDER BY key";
 const LOAD_NEXT_SQL: &str = "SELECT body
-                                     FROM documents
+                                     FROM documents d
                                      WHERE jsonb_array_length(body -> '_outgoing') > 0
+                                     AND split_part(id, '.', 1) = $1
+                                     AND NOT EXISTS (
+                                        SELECT 1 FROM outbox_attempts a
+                                        WHERE a.message_id = d.body -> '_outgoing' -> 0 ->> 'id'
+                                        AND a.next_attempt_at > now()
+                                     )
                                      LIMIT 1
 ";
 const INSERT_SQL: &str = "WITH a as (
@@ -47,6 +88,18 @@ const INSERT_SQL: &str = "WITH a as (
                                 WHERE NOT EXISTS (
                                     SELECT 1 FROM documents d where d.id = a.body ->> '_id'
                                 )";
+// With binary keys, a trigger fills in `kind` and `key` from the id.
+const INSERT_BY_KEY_SQL: &str = "WITH a as (
+                                SELECT $1::jsonb as body
+                                )
+                                INSERT INTO documents AS d (id, body)
+                                SELECT a.body ->> '_id', a.body
+                                FROM a
+                                WHERE NOT EXISTS (
+                                    SELECT 1 FROM documents d
+                                    WHERE d.kind = split_part(a.body ->> '_id', '.', 1)
+                                    AND d.key = id_key(a.body ->> '_id')
+                                )";
 const UPDATE_SQL: &str = "WITH a as (
                                     SELECT $1::jsonb as body, $2::jsonb as expected_version
                                     )
@@ -56,6 +109,33 @@ const UPDATE_SQL: &str = "WITH a as (
                                         WHERE id = a.body ->> '_id'
                                         AND d.body -> '_version' = expected_version
                                     ";
+const UPDATE_BY_KEY_SQL: &str = "WITH a as (
+                                    SELECT $1::jsonb as body, $2::jsonb as expected_version
+                                    )
+                                    UPDATE documents AS d
+                                        SET body = a.body
+                                        FROM a
+                                        WHERE kind = split_part(a.body ->> '_id', '.', 1)
+                                        AND key = id_key(a.body ->> '_id')
+                                        AND d.body -> '_version' = expected_version
+                                    ";
+// Projections read changes in transaction id order, and only once every
+// transaction that might still add an earlier change has finished. A
+// transaction that was assigned its id before a later change to the same
+// document committed would sort before it, so we refuse to record that,
+// as we would for a stale version.
+const RECORD_CHANGE_SQL: &str = "INSERT INTO document_changes (id, body)
+                                    SELECT $1::jsonb ->> '_id', $1::jsonb
+                                    WHERE NOT EXISTS (
+                                        SELECT 1 FROM document_changes c
+                                        WHERE c.id = $1::jsonb ->> '_id'
+                                        AND c.txid > txid_current()
+                                    )";
+const IS_PROCESSED_SQL: &str = "SELECT 1 FROM processed_messages
+                                    WHERE document_id = $1 AND message_id = $2";
+const MARK_PROCESSED_SQL: &str = "INSERT INTO processed_messages (document_id, message_id)
+                                    VALUES ($1, $2)
+                                    ON CONFLICT DO NOTHING";
 
 impl Documents {
     pub fn setup(&self) -> Result<(), Error> {
@@ -65,31 +145,111 @@ impl Documents {
         Ok(())
     }
 
+    /// Moves `documents` from its text primary key to one of each
+    /// document's prefix and the 16 byte binary form of its id, which makes
+    /// for a smaller index, and one that we can scan by creation time. This
+    /// is optional, and cannot be undone. It fails, changing nothing, should
+    /// any document have an id that we cannot decode.
+    ///
+    /// Other connections notice the change when they are next checked out
+    /// of a new pool, and until then keep to the text id, which still works,
+    /// but without an index. So apply this before starting anything else.
+    pub fn setup_binary_keys(&self) -> Result<(), Error> {
+        for stmt in BINARY_KEYS_SQL.split("\n\n") {
+            self.connection.batch_execute(stmt)?;
+        }
+        self.binary_keys.set(Some(true));
+        Ok(())
+    }
+
     pub fn save<D: Serialize + Entity + HasMeta<D>>(&self, document: &mut D) -> Result<(), Error> {
         let t = self.connection.transaction()?;
+        self.save_in(&t, document)?;
+        t.commit()?;
+
+        Ok(())
+    }
+
+    /// Returns whether the document `id` has already handled `envelope`, as
+    /// recorded by `save_processed`.
+    pub fn has_processed<D: Entity, A>(
+        &self,
+        id: &Id<D>,
+        envelope: &Envelope<A>,
+    ) -> Result<bool, Error> {
+        let query = self.connection.prepare_cached(IS_PROCESSED_SQL)?;
+        let res = query.query(&[&id.to_string(), &envelope.id.to_string()])?;
+
+        Ok(!res.is_empty())
+    }
+
+    /// Saves a document that has handled `envelope`, recording the fact in
+    /// the same transaction. Should the message already have been handled by
+    /// this document, nothing is saved, and we return `AlreadyProcessed`.
+    /// This means that redelivering a message has no further effect.
+    pub fn save_processed<D: Serialize + Entity + HasMeta<D>, A>(
+        &self,
+        document: &mut D,
+        envelope: &Envelope<A>,
+    ) -> Result<(), Error> {
+        let t = self.connection.transaction()?;
+
+        let rows = t
+            .prepare_cached(MARK_PROCESSED_SQL)?
+            .execute(&[&document.meta().id.to_string(), &envelope.id.to_string()])?;
+        debug!("Marked processed {} rows", rows);
+        if rows == 0 {
+            return Err(AlreadyProcessed.into());
+        }
+
+        self.save_in(&t, document)?;
+        t.commit()?;
+
+        Ok(())
+    }
+
+    /// Saves a document as part of an existing transaction on this
+    /// connection, such as one used to update a projection.
+    pub fn save_in<D: Serialize + Entity + HasMeta<D>>(
+        &self,
+        t: &Transaction<'_>,
+        document: &mut D,
+    ) -> Result<(), Error> {
         let current_version = document.meta().version.clone();
 
         document.meta_mut().increment_version();
 
         let rows = if current_version == Version::default() {
-            t.prepare_cached(INSERT_SQL)?
-                .execute(&[&Jsonb(&document)])?
+            let insert = self.keyed(INSERT_SQL, INSERT_BY_KEY_SQL)?;
+            t.prepare_cached(insert)?.execute(&[&Jsonb(&document)])?
         } else {
-            t.prepare_cached(UPDATE_SQL)?
+            let update = self.keyed(UPDATE_SQL, UPDATE_BY_KEY_SQL)?;
+            t.prepare_cached(update)?
                 .execute(&[&Jsonb(&document), &Jsonb(&current_version)])?
         };
         debug!("Query modified {} rows", rows);
         if rows == 0 {
             return Err(ConcurrencyError.into());
         }
-        t.commit()?;
+
+        let rows = t
+            .prepare_cached(RECORD_CHANGE_SQL)?
+            .execute(&[&Jsonb(&document)])?;
+        if rows == 0 {
+            return Err(ConcurrencyError.into());
+        }
 
         Ok(())
     }
 
     pub fn load<D: DeserializeOwned + Entity>(&self, id: &Id<D>) -> Result<Option<D>, Error> {
-        let load = self.connection.prepare_cached(LOAD_SQL)?;
-        let res = load.query(&[&id.to_string()])?;
+        let res = if self.has_binary_keys()? {
+            let load = self.connection.prepare_cached(LOAD_BY_KEY_SQL)?;
+            load.query(&[&D::PREFIX, &id.untyped()])?
+        } else {
+            let load = self.connection.prepare_cached(LOAD_SQL)?;
+            load.query(&[&id.to_string()])?
+        };
 
         if let Some(row) = res.iter().next() {
             let Jsonb(doc) = row.get(0);
@@ -100,10 +260,33 @@ impl Documents {
         }
     }
 
-    #[cfg(test)]
+    /// Loads the documents of type `D` whose ids were generated at or after
+    /// `from`, and before `to`, in id order.
+    pub fn load_created_between<D: DeserializeOwned + Entity>(
+        &self,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> Result<Vec<D>, Error> {
+        let load = self
+            .connection
+            .prepare_cached(self.keyed(LOAD_CREATED_SQL, LOAD_CREATED_BY_KEY_SQL)?)?;
+        let res = load.query(&[&D::PREFIX, &UntypedId::min_at(from), &UntypedId::min_at(to)])?;
+
+        let docs = res
+            .iter()
+            .map(|row| {
+                let Jsonb(doc) = row.get(0);
+                doc
+            })
+            .collect();
+        Ok(docs)
+    }
+
+    /// Loads a document of type `D` with outgoing messages, skipping those
+    /// where delivery of the first message is being retried later.
     pub fn load_next_unsent<D: DeserializeOwned + Entity>(&self) -> Result<Option<D>, Error> {
         let load = self.connection.prepare_cached(LOAD_NEXT_SQL)?;
-        let res = load.query(&[])?;
+        let res = load.query(&[&D::PREFIX])?;
         debug!("Cols: {:?}; Rows: {:?}", res.columns(), res.len());
 
         if let Some(row) = res.iter().next() {
@@ -114,6 +297,25 @@ impl Documents {
             Ok(None)
         }
     }
+
+    fn has_binary_keys(&self) -> Result<bool, Error> {
+        if let Some(binary_keys) = self.binary_keys.get() {
+            return Ok(binary_keys);
+        }
+        let rows = self.connection.query(HAS_BINARY_KEYS_SQL, &[])?;
+        let binary_keys = rows.get(0).get(0);
+        self.binary_keys.set(Some(binary_keys));
+        Ok(binary_keys)
+    }
+
+    /// Picks the form of a query for our primary key.
+    fn keyed(&self, text: &'static str, binary: &'static str) -> Result<&'static str, Error> {
+        Ok(if self.has_binary_keys()? {
+            binary
+        } else {
+            text
+        })
+    }
 }
 
 impl Storage for Documents {
@@ -124,6 +326,22 @@ impl Storage for Documents {
     fn save<D: Serialize + Entity + HasMeta<D>>(&self, document: &mut D) -> Result<(), Error> {
         Documents::save(self, document)
     }
+
+    fn has_processed<D: Entity, A>(
+        &self,
+        id: &Id<D>,
+        envelope: &Envelope<A>,
+    ) -> Result<bool, Error> {
+        Documents::has_processed(self, id, envelope)
+    }
+
+    fn save_processed<D: Serialize + Entity + HasMeta<D>, A>(
+        &self,
+        document: &mut D,
+        envelope: &Envelope<A>,
+    ) -> Result<(), Error> {
+        Documents::save_processed(self, document, envelope)
+    }
 }
 
 impl DocumentConnectionManager {
@@ -137,7 +355,11 @@ impl r2d2::ManageConnection for DocumentConnectionManager {
 
     fn connect(&self) -> Result<Self::Connection, Self::Error> {
         let connection = self.0.connect()?;
-        Ok(Documents { connection })
+        let binary_keys = Cell::new(None);
+        Ok(Documents {
+            connection,
+            binary_keys,
+        })
     }
 
     fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
@@ -191,95 +413,16 @@ impl<T: serde::Serialize> fmt::Debug for Jsonb<T> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::clock::ManualClock;
     use crate::documents::*;
-    use crate::ids;
-    use failure::ResultExt;
-    use lazy_static::lazy_static;
-    use r2d2::Pool;
-    use r2d2_postgres::{PostgresConnectionManager, TlsMode};
+    use crate::ids::IdGen;
+    use crate::testing::{pool, IDGEN};
     use rand::random;
     use serde::{Deserialize, Serialize};
-    use std::env;
-
-    lazy_static! {
-        static ref IDGEN: ids::IdGen = ids::IdGen::new();
-    }
-
-    #[derive(Debug)]
-    struct UseTempSchema(String);
-
-    impl r2d2::CustomizeConnection<Documents, postgres::Error> for UseTempSchema {
-        fn on_acquire(&self, conn: &mut Documents) -> Result<(), postgres::Error> {
-            loop {
-                let t = conn.connection.transaction()?;
-                let nschemas: i64 = {
-                    let rows = t.query(
-                        "SELECT count(*) from pg_catalog.pg_namespace n where n.nspname = $1",
-                        &[&self.0],
-                    )?;
-                    let row = rows.get(0);
-                    row.get(0)
-                };
-                debug!("Number of {} schemas:{}", self.0, nschemas);
-                if nschemas == 0 {
-                    match t.execute(&format!("CREATE SCHEMA \"{}\"", self.0), &[]) {
-                        Ok(_) => {
-                            t.commit()?;
-                            break;
-                        }
-                        Err(e) => warn!("Error creating schema:{:?}: {:?}", self.0, e),
-                    }
-                } else {
-                    break;
-                }
-            }
-            conn.connection
-                .execute(&format!("SET search_path TO \"{}\"", self.0), &[])?;
-            Ok(())
-        }
-    }
-
-    fn pool(schema: &str) -> Result<Pool<DocumentConnectionManager>, Error> {
-        debug!("Build pool for {}", schema);
-        let url = env::var("POSTGRES_URL").context("$POSTGRES_URL")?;
-        debug!("Use schema name: {}", schema);
-        let manager = PostgresConnectionManager::new(&*url, TlsMode::None).expect("postgres");
-        let pool = r2d2::Pool::builder()
-            .max_size(2)
-            .connection_customizer(Box::new(UseTempSchema(schema.to_string())))
-            .build(DocumentConnectionManager(manager))?;
-
-        let conn = pool.get()?;
-        cleanup(&conn.connection, schema)?;
-
-        debug!("Init schema in {}", schema);
-        conn.setup()?;
-
-        Ok(pool)
-    }
-
-    fn cleanup(conn: &postgres::Connection, schema: &str) -> Result<(), Error> {
-        let t = conn.transaction()?;
-        debug!("Clean old tables in {}", schema);
-        for row in t
-            .query(
-                "SELECT n.nspname, c.relname \
-                 FROM pg_catalog.pg_class c \
-                 LEFT JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace \
-                 WHERE n.nspname = $1 and c.relkind = 'r'",
-                &[&schema],
-            )?
-            .iter()
-        {
-            let schema = row.get::<_, String>(0);
-            let table = row.get::<_, String>(1);
-            t.execute(&format!("DROP TABLE {}.{}", schema, table), &[])?;
-        }
-        t.commit()?;
-        Ok(())
-    }
+    use std::time::Duration;
 
-    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Entity, HasMeta)]
+    #[entity(prefix = "adocument")]
     struct ADocument {
         #[serde(flatten)]
         meta: DocMeta<ADocument>,
@@ -288,17 +431,6 @@ mod test {
 
     #[derive(Debug, Clone, Default, Hash, PartialEq, Eq, Deserialize, Serialize)]
     struct AMessage;
-    impl Entity for ADocument {
-        const PREFIX: &'static str = "adocument";
-    }
-    impl HasMeta<ADocument> for ADocument {
-        fn meta(&self) -> &DocMeta<Self> {
-            &self.meta
-        }
-        fn meta_mut(&mut self) -> &mut DocMeta<Self> {
-            &mut self.meta
-        }
-    }
 
     #[test]
     fn load_missing_document_should_return_none() -> Result<(), Error> {
@@ -514,7 +646,8 @@ mod test {
         Ok(())
     }
 
-    #[derive(Clone, Debug, Deserialize, Serialize)]
+    #[derive(Clone, Debug, Deserialize, Serialize, Entity, HasMeta)]
+    #[entity(prefix = "chatty")]
     struct ChattyDoc {
         #[serde(flatten)]
         meta: DocMeta<ChattyDoc>,
@@ -522,18 +655,6 @@ mod test {
         mbox: MailBox<AMessage>,
     }
 
-    impl Entity for ChattyDoc {
-        const PREFIX: &'static str = "chatty";
-    }
-    impl HasMeta<ChattyDoc> for ChattyDoc {
-        fn meta(&self) -> &DocMeta<Self> {
-            &self.meta
-        }
-        fn meta_mut(&mut self) -> &mut DocMeta<Self> {
-            &mut self.meta
-        }
-    }
-
     #[test]
     fn should_enqueue_nothing_by_default() -> Result<(), Error> {
         env_logger::try_init().unwrap_or_default();
@@ -567,7 +688,7 @@ mod test {
             mbox: MailBox::default(),
         };
 
-        some_doc.mbox.send(AMessage);
+        some_doc.mbox.send(&IDGEN, AMessage);
         info!("Original document: {:?}", some_doc);
         docs.save(&mut some_doc).expect("save");
 
@@ -595,7 +716,7 @@ mod test {
 
         docs.save(&mut some_doc)?;
 
-        some_doc.mbox.send(AMessage);
+        some_doc.mbox.send(&IDGEN, AMessage);
         info!("Original document: {:?}", some_doc);
         docs.save(&mut some_doc).expect("save");
 
@@ -606,6 +727,164 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn should_preserve_send_order() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("should_preserve_send_order")?;
+        let docs = pool.get()?;
+
+        let mut some_doc = ChattyDoc {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            mbox: MailBox::default(),
+        };
+
+        let sent = (0..4)
+            .map(|_| some_doc.mbox.send(&IDGEN, AMessage))
+            .collect::<Vec<_>>();
+        docs.save(&mut some_doc)?;
+
+        let loaded = docs
+            .load(&some_doc.meta.id)?
+            .ok_or_else(|| failure::err_msg("missing document?"))?;
+        info!("Loaded document: {:?}", loaded);
+
+        let ChattyDoc { mut mbox, .. } = loaded;
+        let received = mbox.drain().map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(sent, received);
+        Ok(())
+    }
+
+    fn some_envelope() -> Envelope<AMessage> {
+        let id = IDGEN.untyped();
+        Envelope {
+            id,
+            seq: 0,
+            correlation_id: id,
+            causation_id: None,
+            message: AMessage,
+        }
+    }
+
+    #[test]
+    fn should_not_have_processed_unseen_message() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("should_not_have_processed_unseen_message")?;
+        let docs = pool.get()?;
+
+        let id = IDGEN.generate::<ADocument>();
+        let processed = docs.has_processed(&id, &some_envelope())?;
+
+        assert!(!processed, "Should not have processed message");
+        Ok(())
+    }
+
+    #[test]
+    fn should_record_processed_message() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("should_record_processed_message")?;
+        let docs = pool.get()?;
+
+        let envelope = some_envelope();
+        let mut some_doc = ADocument {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            name: "Receiver".to_string(),
+        };
+
+        docs.save_processed(&mut some_doc, &envelope)?;
+
+        assert!(docs.has_processed(&some_doc.meta.id, &envelope)?);
+        let loaded = docs.load(&some_doc.meta.id)?;
+        assert_eq!(Some(some_doc), loaded);
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_redelivered_message() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("should_reject_redelivered_message")?;
+        let docs = pool.get()?;
+
+        let envelope = some_envelope();
+        let mut some_doc = ADocument {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            name: "Version 1".to_string(),
+        };
+        docs.save_processed(&mut some_doc, &envelope)?;
+
+        let mut modified_doc = ADocument {
+            name: "Version 2".to_string(),
+            ..some_doc.clone()
+        };
+        let err = docs
+            .save_processed(&mut modified_doc, &envelope)
+            .expect_err("save should fail");
+
+        assert_eq!(
+            err.find_root_cause().downcast_ref::<AlreadyProcessed>(),
+            Some(&AlreadyProcessed),
+            "Error: {:?}",
+            err
+        );
+        let loaded = docs.load(&some_doc.meta.id)?;
+        assert_eq!(Some(some_doc), loaded);
+        Ok(())
+    }
+
+    #[test]
+    fn should_track_processing_per_document() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("should_track_processing_per_document")?;
+        let docs = pool.get()?;
+
+        let envelope = some_envelope();
+        let mut first = ADocument {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            name: "First".to_string(),
+        };
+        let mut second = ADocument {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            name: "Second".to_string(),
+        };
+
+        docs.save_processed(&mut first, &envelope)?;
+        assert!(!docs.has_processed(&second.meta.id, &envelope)?);
+        docs.save_processed(&mut second, &envelope)?;
+
+        assert!(docs.has_processed(&second.meta.id, &envelope)?);
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_record_processing_when_save_fails() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("should_not_record_processing_when_save_fails")?;
+        let docs = pool.get()?;
+
+        let envelope = some_envelope();
+        let mut some_doc = ADocument {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            name: "Version 1".to_string(),
+        };
+        docs.save(&mut some_doc)?;
+
+        let mut stale_doc = ADocument {
+            meta: DocMeta::new_with_id(some_doc.meta.id),
+            name: "Stale".to_string(),
+        };
+        let err = docs
+            .save_processed(&mut stale_doc, &envelope)
+            .expect_err("save should fail");
+
+        assert_eq!(
+            err.find_root_cause().downcast_ref::<ConcurrencyError>(),
+            Some(&ConcurrencyError),
+            "Error: {:?}",
+            err
+        );
+        assert!(!docs.has_processed(&some_doc.meta.id, &envelope)?);
+        Ok(())
+    }
+
     #[test]
     #[ignore]
     fn should_enqueue_something_something() -> Result<(), Error> {
@@ -616,7 +895,7 @@ mod test {
             meta: DocMeta::new_with_id(IDGEN.generate()),
             mbox: MailBox::default(),
         };
-        some_doc.mbox.send(AMessage);
+        some_doc.mbox.send(&IDGEN, AMessage);
 
         let docs = pool.get()?;
         info!("Original document: {:?}", some_doc);
@@ -625,7 +904,7 @@ mod test {
 
         let doc = docs
             .load_next_unsent::<ChattyDoc>()?
-            .ok_or_else(|| failure::err_msg("missing document?"))?;;
+            .ok_or_else(|| failure::err_msg("missing document?"))?;
         info!("Loaded something: {:?}", doc);
 
         assert_eq!(doc.meta.id, some_doc.meta.id);
@@ -634,6 +913,260 @@ mod test {
     }
 
     #[test]
-    #[ignore]
-    fn should_only_load_messages_of_type() {}
+    fn should_only_load_messages_of_type() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("should_only_load_messages_of_type")?;
+        let docs = pool.get()?;
+
+        let mut some_doc = ChattyDoc {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            mbox: MailBox::default(),
+        };
+        some_doc.mbox.send(&IDGEN, AMessage);
+        docs.save(&mut some_doc)?;
+
+        let loaded = docs.load_next_unsent::<ADocument>()?;
+        info!("Loaded something: {:?}", loaded);
+
+        assert!(
+            loaded.is_none(),
+            "Should find no document. Got: {:?}",
+            loaded
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn untyped_ids_should_round_trip_as_uuid_and_bytea() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("untyped_ids_should_round_trip_as_uuid_and_bytea")?;
+        let docs = pool.get()?;
+
+        let id = IDGEN.untyped();
+        let rows = docs
+            .connection
+            .query("SELECT $1::uuid, $2::bytea", &[&id, &id])?;
+        let row = rows.get(0);
+        assert_eq!(row.get::<_, UntypedId>(0), id);
+        assert_eq!(row.get::<_, UntypedId>(1), id);
+        Ok(())
+    }
+
+    #[test]
+    fn untyped_ids_should_sort_in_id_order() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("untyped_ids_should_sort_in_id_order")?;
+        let docs = pool.get()?;
+
+        let mut ids = (0..20)
+            .map(|_| UntypedId::hashed(random::<u64>()))
+            .chain((0..20).map(|_| IDGEN.untyped()))
+            .collect::<Vec<_>>();
+        docs.connection
+            .batch_execute("CREATE TABLE sorted (id uuid NOT NULL)")?;
+        for id in ids.iter() {
+            docs.connection
+                .execute("INSERT INTO sorted (id) VALUES ($1)", &[id])?;
+        }
+
+        let rows = docs
+            .connection
+            .query("SELECT id FROM sorted ORDER BY id", &[])?;
+        let sorted = rows
+            .iter()
+            .map(|row| row.get(0))
+            .collect::<Vec<UntypedId>>();
+        ids.sort();
+        assert_eq!(sorted, ids);
+        Ok(())
+    }
+
+    #[test]
+    fn ids_should_round_trip_as_text() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("ids_should_round_trip_as_text")?;
+        let docs = pool.get()?;
+
+        let id = IDGEN.generate::<ADocument>();
+        let rows = docs.connection.query("SELECT $1::text", &[&id])?;
+        assert_eq!(rows.get(0).get::<_, Id<ADocument>>(0), id);
+        Ok(())
+    }
+
+    #[test]
+    fn id_key_should_match_binary_form() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("id_key_should_match_binary_form")?;
+        let docs = pool.get()?;
+
+        let id = IDGEN.generate::<ADocument>();
+        let rows = docs.connection.query(
+            "SELECT id_key($1), id_key($2)",
+            &[&id, &id.untyped().to_string()],
+        )?;
+        let row = rows.get(0);
+        assert_eq!(row.get::<_, UntypedId>(0), id.untyped());
+        assert_eq!(row.get::<_, UntypedId>(1), id.untyped());
+        Ok(())
+    }
+
+    #[test]
+    fn should_key_documents_by_binary_id() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("should_key_documents_by_binary_id")?;
+        let docs = pool.get()?;
+
+        let mut before = ADocument {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            name: "Before".to_string(),
+        };
+        docs.save(&mut before)?;
+
+        docs.setup_binary_keys()?;
+        // Should be idempotent, like `setup`.
+        docs.setup_binary_keys()?;
+
+        let rows = docs.connection.query(
+            "SELECT a.attname FROM pg_index i
+             JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = ANY(i.indkey)
+             WHERE i.indrelid = 'documents'::regclass AND i.indisprimary
+             ORDER BY a.attname",
+            &[],
+        )?;
+        let key = rows.iter().map(|r| r.get(0)).collect::<Vec<String>>();
+        assert_eq!(key, vec!["key", "kind"]);
+
+        // Documents saved before the migration are keyed by it.
+        before.name = "Updated".to_string();
+        docs.save(&mut before)?;
+        let loaded = docs.load(&before.meta.id)?;
+        assert_eq!(Some("Updated".to_string()), loaded.map(|d| d.name));
+
+        let mut after = ADocument {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            name: "After".to_string(),
+        };
+        docs.save(&mut after)?;
+        assert_eq!(Some(after.clone()), docs.load(&after.meta.id)?);
+        // A document of another kind may share the binary id.
+        let mut chatty = ChattyDoc {
+            meta: DocMeta::new_with_id(Id::from_untyped(after.meta.id.untyped())),
+            mbox: MailBox::default(),
+        };
+        docs.save(&mut chatty)?;
+        assert!(docs.load(&chatty.meta.id)?.is_some());
+        assert_eq!(Some(after.clone()), docs.load(&after.meta.id)?);
+
+        // A stale version is refused, as before.
+        let mut stale = before.clone();
+        docs.save(&mut before)?;
+        let err = docs.save(&mut stale).expect_err("stale save");
+        assert!(
+            err.downcast_ref::<ConcurrencyError>().is_some(),
+            "{:?}",
+            err
+        );
+
+        let loaded =
+            docs.load_created_between::<ADocument>(SystemTime::UNIX_EPOCH, SystemTime::now())?;
+        assert_eq!(
+            loaded.into_iter().map(|d| d.name).collect::<Vec<_>>(),
+            vec!["Updated", "After"]
+        );
+
+        docs.connection.batch_execute("SET enable_seqscan = off")?;
+        let rows = docs.connection.query(
+            &format!("EXPLAIN {}", LOAD_BY_KEY_SQL),
+            &[&ADocument::PREFIX, &after.meta.id.untyped()],
+        )?;
+        let plan = rows
+            .iter()
+            .map(|row| row.get::<_, String>(0))
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert!(plan.contains("documents_pkey"), "Plan: {}", plan);
+        Ok(())
+    }
+
+    #[test]
+    fn should_load_documents_created_in_range() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("should_load_documents_created_in_range")?;
+        let docs = pool.get()?;
+
+        let nine = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let clock = ManualClock::new(nine - Duration::from_secs(60));
+        let idgen = IdGen::with_clock(clock.clone(), 0);
+        let mut names = Vec::new();
+        for minutes in &[0, 30, 59, 60, 90] {
+            clock.set(nine + Duration::from_secs(minutes * 60));
+            let name = format!("at {}", minutes);
+            docs.save(&mut ADocument {
+                meta: DocMeta::new_with_id(idgen.generate()),
+                name: name.clone(),
+            })?;
+            docs.save(&mut ChattyDoc {
+                meta: DocMeta::new_with_id(idgen.generate()),
+                mbox: MailBox::default(),
+            })?;
+            names.push(name);
+        }
+
+        let ten = nine + Duration::from_secs(3600);
+        let loaded = docs.load_created_between::<ADocument>(nine, ten)?;
+        assert_eq!(
+            loaded.into_iter().map(|d| d.name).collect::<Vec<_>>(),
+            &names[0..3]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_index_documents_by_id_time() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("should_index_documents_by_id_time")?;
+        let docs = pool.get()?;
+
+        docs.connection.batch_execute("SET enable_seqscan = off")?;
+        let rows = docs.connection.query(
+            &format!("EXPLAIN {}", LOAD_CREATED_SQL),
+            &[
+                &ADocument::PREFIX,
+                &UntypedId::min_at(SystemTime::UNIX_EPOCH),
+                &UntypedId::min_at(SystemTime::now()),
+            ],
+        )?;
+        let plan = rows
+            .iter()
+            .map(|row| row.get::<_, String>(0))
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert!(plan.contains("documents_by_id_time"), "Plan: {}", plan);
+        Ok(())
+    }
+
+    #[test]
+    fn should_index_documents_with_malformed_ids() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("should_index_documents_with_malformed_ids")?;
+        let docs = pool.get()?;
+
+        for id in &[
+            "adocument.short",
+            "adocument.0000000000001q5nnvfqq7krfZ",
+            "legacy",
+        ] {
+            let rows = docs.connection.query("SELECT id_key($1) IS NULL", &[id])?;
+            assert!(rows.get(0).get::<_, bool>(0), "id_key({:?})", id);
+            docs.connection.execute(
+                "INSERT INTO documents (id, body) VALUES ($1, jsonb_build_object('_id', $1::text))",
+                &[id],
+            )?;
+        }
+
+        let loaded =
+            docs.load_created_between::<ADocument>(SystemTime::UNIX_EPOCH, SystemTime::now())?;
+        assert_eq!(loaded, vec![]);
+        Ok(())
+    }
 }
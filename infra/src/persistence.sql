@@ -54,4 +54,104 @@ $$);
 SELECT apply_migration(text '0004 Add index for outbox', text $$
     CREATE INDEX ON documents (jsonb_array_length(body -> '_outgoing'))
         WHERE jsonb_array_length(body -> '_outgoing') > 0
-$$);
\ No newline at end of file
+$$);
+
+SELECT apply_migration(text '0005 Add inbox of processed messages', text $$
+    CREATE TABLE IF NOT EXISTS processed_messages (
+        document_id TEXT,
+        message_id TEXT,
+        PRIMARY KEY(document_id, message_id)
+    );
+$$);
+
+SELECT apply_migration(text '0006 Add outbox retries and dead letters', text $$
+    CREATE TABLE IF NOT EXISTS outbox_attempts (
+        message_id TEXT PRIMARY KEY,
+        document_id TEXT NOT NULL,
+        attempts INTEGER NOT NULL,
+        next_attempt_at TIMESTAMPTZ NOT NULL,
+        last_error TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS dead_letters (
+        message_id TEXT PRIMARY KEY,
+        document_id TEXT NOT NULL,
+        envelope jsonb NOT NULL,
+        attempts INTEGER NOT NULL,
+        last_error TEXT NOT NULL,
+        failed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    );
+$$);
+
+SELECT apply_migration(text '0007 Add index for saga timeouts', text $$
+    CREATE INDEX ON documents (((body ->> '_timeout_at')::bigint))
+        WHERE (body ->> '_timeout_at') IS NOT NULL
+$$);
+
+SELECT apply_migration(text '0008 Add document change log for projections', text $$
+    CREATE TABLE IF NOT EXISTS document_changes (
+        seq BIGSERIAL PRIMARY KEY,
+        txid BIGINT NOT NULL DEFAULT txid_current(),
+        id TEXT NOT NULL,
+        body jsonb NOT NULL
+    );
+    CREATE INDEX ON document_changes (split_part(id, '.', 1), txid, seq);
+    CREATE INDEX ON document_changes (id, txid);
+    CREATE TABLE IF NOT EXISTS projection_checkpoints (
+        name TEXT PRIMARY KEY,
+        txid BIGINT NOT NULL,
+        seq BIGINT NOT NULL
+    );
+$$);
+
+SELECT apply_migration(text '0009 Add event streams', text $$
+    CREATE TABLE IF NOT EXISTS events (
+        stream_id TEXT NOT NULL,
+        position BIGINT NOT NULL,
+        body jsonb NOT NULL,
+        recorded_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        PRIMARY KEY (stream_id, position)
+    );
+$$);
+
+SELECT apply_migration(text '0010 Add id_key function', text $$
+    -- Decodes the base32hex portion of an id (after any prefix) to the same
+    -- 16 bytes as UntypedId's binary form, so keys sort in id order. Ids
+    -- that we cannot decode have no key, rather than failing the query or
+    -- insert that indexes them.
+    CREATE OR REPLACE FUNCTION id_key(id TEXT) RETURNS uuid AS $f$
+    DECLARE
+        encoded TEXT := regexp_replace(id, '^.*\.', '');
+        bytes BYTEA := decode(repeat('00', 16), 'hex');
+        acc INTEGER := 0;
+        bits INTEGER := 0;
+        pos INTEGER := 0;
+        val INTEGER;
+    BEGIN
+        IF length(encoded) <> 26 THEN
+            RETURN NULL;
+        END IF;
+        FOR i IN 1..length(encoded) LOOP
+            val := strpos('0123456789abcdefghijklmnopqrstuv', substr(encoded, i, 1)) - 1;
+            IF val < 0 THEN
+                RETURN NULL;
+            END IF;
+            acc := (acc << 5) | val;
+            bits := bits + 5;
+            IF bits >= 8 THEN
+                bits := bits - 8;
+                IF pos < 16 THEN
+                    bytes := set_byte(bytes, pos, (acc >> bits) & 255);
+                END IF;
+                pos := pos + 1;
+                acc := acc & ((1 << bits) - 1);
+            END IF;
+        END LOOP;
+        RETURN encode(bytes, 'hex')::uuid;
+    END
+    $f$ LANGUAGE plpgsql IMMUTABLE STRICT;
+$$);
+
+SELECT apply_migration(text '0011 Index documents by id time', text $$
+    CREATE INDEX IF NOT EXISTS documents_by_id_time
+        ON documents (split_part(id, '.', 1), id_key(id));
+$$);
@@ -0,0 +1,540 @@
+use failure::Error;
+use log::*;
+use postgres::transaction::Transaction;
+use serde::de::DeserializeOwned;
+
+use crate::ids::Entity;
+use crate::persistence::{Documents, Jsonb};
+
+/// Maintains a read model, such as a table of per-election turnout, from
+/// the changes made to documents of a single type. Each projection keeps a
+/// checkpoint, so it sees the changes in the order they were made, and
+/// starts from the documents themselves when it is new or rebuilt.
+pub trait Projector {
+    type Document: DeserializeOwned + Entity;
+
+    /// Names the projection's checkpoint; this must be unique.
+    fn name(&self) -> &str;
+    /// Creates whatever storage the projection needs, if it does not already
+    /// exist.
+    fn setup(&self, t: &Transaction<'_>) -> Result<(), Error>;
+    /// Discards everything the projection has built, ahead of a rebuild.
+    fn reset(&self, t: &Transaction<'_>) -> Result<(), Error>;
+    /// Updates the projection with a newly saved version of a document. Just
+    /// after a rebuild, we may pass a version that it has already seen.
+    fn apply(&self, t: &Transaction<'_>, document: &Self::Document) -> Result<(), Error>;
+}
+
+/// A type-erased projector, so that we can keep a list of them.
+pub trait Projection {
+    fn name(&self) -> &str;
+    /// Applies any changes made since the projection's checkpoint, returning
+    /// how many there were.
+    fn catch_up(&self, docs: &Documents) -> Result<usize, Error>;
+    /// Rebuilds the projection from the current documents.
+    fn rebuild(&self, docs: &Documents) -> Result<usize, Error>;
+}
+
+const BATCH_SIZE: i64 = 100;
+
+// Every transaction with an id below this has finished, so no more changes
+// can appear before it.
+const HORIZON_SQL: &str = "SELECT txid_snapshot_xmin(txid_current_snapshot())::bigint";
+const INIT_CHECKPOINT_SQL: &str = "INSERT INTO projection_checkpoints (name, txid, seq)
+                                    VALUES ($1, 0, 0)
+                                    ON CONFLICT DO NOTHING";
+const LOAD_CHECKPOINT_SQL: &str = "SELECT txid, seq FROM projection_checkpoints
+                                    WHERE name = $1
+                                    FOR UPDATE";
+const STORE_CHECKPOINT_SQL: &str = "UPDATE projection_checkpoints
+                                    SET txid = $2, seq = $3
+                                    WHERE name = $1";
+const LOAD_CHANGES_SQL: &str = "SELECT txid, seq, body FROM document_changes
+                                    WHERE split_part(id, '.', 1) = $1
+                                    AND (txid, seq) > ($2, $3)
+                                    AND txid < $4
+                                    ORDER BY txid, seq
+                                    LIMIT $5";
+const LOAD_DOCUMENTS_SQL: &str = "SELECT id, body FROM documents
+                                    WHERE split_part(id, '.', 1) = $1
+                                    AND id > $2
+                                    ORDER BY id
+                                    LIMIT $3";
+// Stops projections from starting or moving whilst we prune.
+const LOCK_CHECKPOINTS_SQL: &str = "LOCK TABLE projection_checkpoints IN EXCLUSIVE MODE";
+const FORGET_CHECKPOINTS_SQL: &str = "DELETE FROM projection_checkpoints
+                                    WHERE NOT (name = ANY($1))";
+const LOAD_OLDEST_CHECKPOINT_SQL: &str = "SELECT txid, seq FROM projection_checkpoints
+                                    ORDER BY txid, seq
+                                    LIMIT 1";
+const PRUNE_CHANGES_SQL: &str = "DELETE FROM document_changes
+                                    WHERE (txid, seq) < ($1, $2)";
+
+impl Documents {
+    /// Applies any changes made since `projector`'s checkpoint, returning how
+    /// many there were. A projection that we have not seen before is built
+    /// from the current documents first.
+    pub fn catch_up_projection<P: Projector>(&self, projector: &P) -> Result<usize, Error> {
+        let name = Projector::name(projector);
+        let mut applied = 0;
+        {
+            let t = self.connection.transaction()?;
+            projector.setup(&t)?;
+            let created = t.prepare_cached(INIT_CHECKPOINT_SQL)?.execute(&[&name])?;
+            if created > 0 {
+                projector.reset(&t)?;
+                applied += Self::apply_documents(&t, projector)?;
+            }
+            t.commit()?;
+        }
+
+        loop {
+            let batch = self.apply_changes(projector)?;
+            applied += batch;
+            if batch < BATCH_SIZE as usize {
+                break;
+            }
+        }
+
+        debug!("Applied {} changes to {}", applied, name);
+        Ok(applied)
+    }
+
+    /// Clears out `projector`'s storage, and builds it again from the
+    /// current documents, before applying any changes made since.
+    pub fn rebuild_projection<P: Projector>(&self, projector: &P) -> Result<usize, Error> {
+        let name = Projector::name(projector);
+        let applied = {
+            let t = self.connection.transaction()?;
+            projector.setup(&t)?;
+            projector.reset(&t)?;
+            t.prepare_cached(INIT_CHECKPOINT_SQL)?.execute(&[&name])?;
+            let applied = Self::apply_documents(&t, projector)?;
+            t.commit()?;
+            applied
+        };
+        info!("Rebuilt projection {} from {} documents", name, applied);
+
+        Ok(applied + self.catch_up_projection(projector)?)
+    }
+
+    /// Deletes the changes that every one of the `maintained` projections has
+    /// applied, returning how many there were. Checkpoints for any other
+    /// projections are discarded, so they will be rebuilt should they come
+    /// back.
+    pub fn prune_changes(&self, maintained: &[&str]) -> Result<u64, Error> {
+        let t = self.connection.transaction()?;
+        t.prepare_cached(LOCK_CHECKPOINTS_SQL)?.execute(&[])?;
+        let forgotten = t
+            .prepare_cached(FORGET_CHECKPOINTS_SQL)?
+            .execute(&[&maintained])?;
+        if forgotten > 0 {
+            info!(
+                "Forgot {} checkpoints of unmaintained projections",
+                forgotten
+            );
+        }
+
+        let (txid, seq): (i64, i64) = {
+            let rows = t.prepare_cached(LOAD_OLDEST_CHECKPOINT_SQL)?.query(&[])?;
+            match rows.iter().next() {
+                Some(row) => (row.get(0), row.get(1)),
+                None => (Self::horizon(&t)?, 0),
+            }
+        };
+        let pruned = t
+            .prepare_cached(PRUNE_CHANGES_SQL)?
+            .execute(&[&txid, &seq])?;
+        t.commit()?;
+
+        debug!("Pruned {} changes", pruned);
+        Ok(pruned)
+    }
+
+    // Changes from transactions below the horizon will be reflected in the
+    // documents that we read, so the checkpoint can skip past them.
+    fn apply_documents<P: Projector>(t: &Transaction<'_>, projector: &P) -> Result<usize, Error> {
+        let horizon = Self::horizon(t)?;
+        let load = t.prepare_cached(LOAD_DOCUMENTS_SQL)?;
+        let mut last = String::new();
+        let mut applied = 0;
+        loop {
+            let rows = load.query(&[&P::Document::PREFIX, &last, &BATCH_SIZE])?;
+            for row in rows.iter() {
+                let Jsonb(document) = row.get(1);
+                projector.apply(t, &document)?;
+                last = row.get(0);
+                applied += 1;
+            }
+            if rows.len() < BATCH_SIZE as usize {
+                break;
+            }
+        }
+
+        t.prepare_cached(STORE_CHECKPOINT_SQL)?.execute(&[
+            &Projector::name(projector),
+            &horizon,
+            &0i64,
+        ])?;
+        Ok(applied)
+    }
+
+    fn apply_changes<P: Projector>(&self, projector: &P) -> Result<usize, Error> {
+        let name = Projector::name(projector);
+        let t = self.connection.transaction()?;
+
+        let checkpoint: (i64, i64) = {
+            let rows = t.prepare_cached(LOAD_CHECKPOINT_SQL)?.query(&[&name])?;
+            rows.iter()
+                .next()
+                .map(|row| (row.get(0), row.get(1)))
+                .unwrap_or((0, 0))
+        };
+        let horizon = Self::horizon(&t)?;
+
+        let mut last = checkpoint;
+        let mut applied = 0;
+        {
+            let load = t.prepare_cached(LOAD_CHANGES_SQL)?;
+            let rows = load.query(&[
+                &P::Document::PREFIX,
+                &checkpoint.0,
+                &checkpoint.1,
+                &horizon,
+                &BATCH_SIZE,
+            ])?;
+            for row in rows.iter() {
+                let position: (i64, i64) = (row.get(0), row.get(1));
+                let Jsonb(document) = row.get(2);
+                trace!("Applying change {:?} to {}", position, name);
+                projector.apply(&t, &document)?;
+                last = position;
+                applied += 1;
+            }
+        }
+
+        // Once we have caught up, we can skip over changes to other types of
+        // document, so that they can be pruned.
+        if applied < BATCH_SIZE as usize {
+            last = (horizon, 0);
+        }
+        if last != checkpoint {
+            t.prepare_cached(STORE_CHECKPOINT_SQL)?
+                .execute(&[&name, &last.0, &last.1])?;
+        }
+        t.commit()?;
+
+        Ok(applied)
+    }
+
+    fn horizon(t: &Transaction<'_>) -> Result<i64, Error> {
+        let rows = t.prepare_cached(HORIZON_SQL)?.query(&[])?;
+        Ok(rows.get(0).get(0))
+    }
+}
+
+impl<P: Projector> Projection for P {
+    fn name(&self) -> &str {
+        Projector::name(self)
+    }
+
+    fn catch_up(&self, docs: &Documents) -> Result<usize, Error> {
+        docs.catch_up_projection(self)
+    }
+
+    fn rebuild(&self, docs: &Documents) -> Result<usize, Error> {
+        docs.rebuild_projection(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::documents::*;
+    use crate::persistence::ConcurrencyError;
+    use crate::testing::{pool, IDGEN};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Entity, HasMeta)]
+    #[entity(prefix = "voter")]
+    struct Voter {
+        #[serde(flatten)]
+        meta: DocMeta<Voter>,
+        election: String,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Entity, HasMeta)]
+    #[entity(prefix = "bystander")]
+    struct Bystander {
+        #[serde(flatten)]
+        meta: DocMeta<Bystander>,
+        election: String,
+    }
+
+    struct Turnout;
+
+    impl Projector for Turnout {
+        type Document = Voter;
+
+        fn name(&self) -> &str {
+            "turnout"
+        }
+
+        fn setup(&self, t: &Transaction<'_>) -> Result<(), Error> {
+            t.batch_execute(
+                "CREATE TABLE IF NOT EXISTS turnout (
+                    voter TEXT PRIMARY KEY,
+                    election TEXT NOT NULL
+                )",
+            )?;
+            Ok(())
+        }
+
+        fn reset(&self, t: &Transaction<'_>) -> Result<(), Error> {
+            t.batch_execute("TRUNCATE turnout")?;
+            Ok(())
+        }
+
+        fn apply(&self, t: &Transaction<'_>, voter: &Voter) -> Result<(), Error> {
+            t.execute(
+                "INSERT INTO turnout (voter, election) VALUES ($1, $2)
+                ON CONFLICT (voter) DO UPDATE SET election = EXCLUDED.election",
+                &[&voter.meta.id.to_string(), &voter.election],
+            )?;
+            Ok(())
+        }
+    }
+
+    fn voter(election: &str) -> Voter {
+        Voter {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            election: election.to_string(),
+        }
+    }
+
+    // Waits for every transaction that started before now to finish, such
+    // as those of other tests, so that catching up sees all of our changes.
+    fn settle(docs: &Documents) -> Result<(), Error> {
+        let now: i64 = docs
+            .connection
+            .query("SELECT txid_current()::bigint", &[])?
+            .get(0)
+            .get(0);
+        loop {
+            let t = docs.connection.transaction()?;
+            if Documents::horizon(&t)? > now {
+                return Ok(());
+            }
+            drop(t);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    fn changes(docs: &Documents) -> Result<i64, Error> {
+        let rows = docs
+            .connection
+            .query("SELECT count(*) FROM document_changes", &[])?;
+        Ok(rows.get(0).get(0))
+    }
+
+    fn turnout(docs: &Documents) -> Result<Vec<(String, i64)>, Error> {
+        let rows = docs.connection.query(
+            "SELECT election, count(*) FROM turnout GROUP BY election ORDER BY election",
+            &[],
+        )?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    #[test]
+    fn should_project_saved_documents() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("projections_should_project_saved_documents")?;
+        let docs = pool.get()?;
+
+        docs.save(&mut voter("a"))?;
+        docs.save(&mut voter("a"))?;
+        docs.save(&mut voter("b"))?;
+
+        settle(&docs)?;
+        assert_eq!(docs.catch_up_projection(&Turnout)?, 3);
+        assert_eq!(
+            turnout(&docs)?,
+            vec![("a".to_string(), 2), ("b".to_string(), 1)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_only_apply_new_changes() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("projections_should_only_apply_new_changes")?;
+        let docs = pool.get()?;
+
+        docs.save(&mut voter("a"))?;
+        settle(&docs)?;
+        assert_eq!(docs.catch_up_projection(&Turnout)?, 1);
+        assert_eq!(docs.catch_up_projection(&Turnout)?, 0);
+
+        docs.save(&mut voter("b"))?;
+        settle(&docs)?;
+        assert_eq!(docs.catch_up_projection(&Turnout)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn should_apply_changes_in_order() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("projections_should_apply_changes_in_order")?;
+        let docs = pool.get()?;
+        docs.catch_up_projection(&Turnout)?;
+
+        let mut some_voter = voter("a");
+        docs.save(&mut some_voter)?;
+        some_voter.election = "b".to_string();
+        docs.save(&mut some_voter)?;
+
+        settle(&docs)?;
+        assert_eq!(docs.catch_up_projection(&Turnout)?, 2);
+        assert_eq!(turnout(&docs)?, vec![("b".to_string(), 1)]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_ignore_other_document_types() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("projections_should_ignore_other_document_types")?;
+        let docs = pool.get()?;
+
+        docs.save(&mut Bystander {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            election: "a".to_string(),
+        })?;
+
+        assert_eq!(docs.catch_up_projection(&Turnout)?, 0);
+        assert_eq!(turnout(&docs)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_rebuild_from_scratch() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("projections_should_rebuild_from_scratch")?;
+        let docs = pool.get()?;
+
+        docs.save(&mut voter("a"))?;
+        docs.save(&mut voter("b"))?;
+        settle(&docs)?;
+        docs.catch_up_projection(&Turnout)?;
+        docs.connection
+            .execute("DELETE FROM turnout WHERE election = 'a'", &[])?;
+
+        let projection: &dyn Projection = &Turnout;
+        settle(&docs)?;
+        assert_eq!(projection.rebuild(&docs)?, 2);
+        assert_eq!(
+            turnout(&docs)?,
+            vec![("a".to_string(), 1), ("b".to_string(), 1)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_wait_for_earlier_transactions() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("projections_should_wait_for_earlier_transactions")?;
+        let docs = pool.get()?;
+        let slow = pool.get()?;
+        docs.catch_up_projection(&Turnout)?;
+
+        let t = slow.connection.transaction()?;
+        slow.save_in(&t, &mut voter("a"))?;
+        docs.save(&mut voter("b"))?;
+        assert_eq!(docs.catch_up_projection(&Turnout)?, 0);
+
+        t.commit()?;
+        settle(&docs)?;
+        assert_eq!(docs.catch_up_projection(&Turnout)?, 2);
+        assert_eq!(
+            turnout(&docs)?,
+            vec![("a".to_string(), 1), ("b".to_string(), 1)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_refuse_change_that_would_sort_before_a_later_one() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("projections_should_refuse_out_of_order_change")?;
+        let docs = pool.get()?;
+        let slow = pool.get()?;
+
+        let t = slow.connection.transaction()?;
+        t.execute("SELECT txid_current()", &[])?;
+        let mut some_voter = voter("a");
+        docs.save(&mut some_voter)?;
+
+        some_voter.election = "b".to_string();
+        let err = slow
+            .save_in(&t, &mut some_voter)
+            .expect_err("save should fail");
+        assert!(
+            err.downcast_ref::<ConcurrencyError>().is_some(),
+            "Error: {:?}",
+            err
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_prune_changes_once_applied() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("projections_should_prune_changes_once_applied")?;
+        let docs = pool.get()?;
+
+        docs.save(&mut voter("a"))?;
+        docs.save(&mut Bystander {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            election: "a".to_string(),
+        })?;
+        settle(&docs)?;
+        assert_eq!(docs.prune_changes(&["turnout"])?, 2);
+        assert_eq!(changes(&docs)?, 0);
+
+        settle(&docs)?;
+        assert_eq!(docs.catch_up_projection(&Turnout)?, 1);
+        docs.save(&mut voter("b"))?;
+        assert_eq!(docs.prune_changes(&["turnout"])?, 0);
+
+        settle(&docs)?;
+        assert_eq!(docs.catch_up_projection(&Turnout)?, 1);
+        docs.prune_changes(&["turnout"])?;
+        assert_eq!(changes(&docs)?, 0);
+        assert_eq!(
+            turnout(&docs)?,
+            vec![("a".to_string(), 1), ("b".to_string(), 1)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_rebuild_projections_that_are_no_longer_maintained() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("projections_should_rebuild_unmaintained")?;
+        let docs = pool.get()?;
+
+        docs.save(&mut voter("a"))?;
+        settle(&docs)?;
+        docs.catch_up_projection(&Turnout)?;
+        docs.prune_changes(&[])?;
+
+        docs.save(&mut voter("b"))?;
+        settle(&docs)?;
+        docs.prune_changes(&[])?;
+        assert_eq!(docs.catch_up_projection(&Turnout)?, 2);
+        assert_eq!(
+            turnout(&docs)?,
+            vec![("a".to_string(), 1), ("b".to_string(), 1)]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+use std::fmt;
+use std::hash::Hasher;
+use std::marker::PhantomData;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use siphasher::sip::SipHasher24;
+
+use crate::hash_keys::HashKey;
+use crate::ids::{encoded_offset, Entity, Id, IdParseError, DIVIDER};
+use crate::untyped_ids::UntypedId;
+
+/// Marks the public form of an id, ahead of the entity prefix.
+const PUBLIC_PREFIX: &str = "pub-";
+const ROUNDS: u8 = 8;
+
+/// The form of an `Id<T>` that we show outside of the server, such as in
+/// URLs. It is encrypted, so reveals neither when the entity was created,
+/// nor anything of its random portion.
+#[derive(Debug)]
+pub struct PublicId<T> {
+    inner: UntypedId,
+    phantom: PhantomData<T>,
+}
+
+/// The secret key for converting between ids and their public form.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct PublicIdKey(HashKey);
+
+impl PublicIdKey {
+    pub fn new(key: HashKey) -> Self {
+        PublicIdKey(key)
+    }
+
+    pub fn generate() -> Self {
+        PublicIdKey(HashKey::generate())
+    }
+
+    pub fn public<T>(&self, id: Id<T>) -> PublicId<T> {
+        let UntypedId { stamp, random } = id.untyped();
+        let (mut left, mut right) = (stamp, random);
+        for round in 0..ROUNDS {
+            let next = left ^ self.round(round, right);
+            left = right;
+            right = next;
+        }
+        PublicId {
+            inner: UntypedId {
+                stamp: left,
+                random: right,
+            },
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn private<T>(&self, id: PublicId<T>) -> Id<T> {
+        let UntypedId { stamp, random } = id.inner;
+        let (mut left, mut right) = (stamp, random);
+        for round in (0..ROUNDS).rev() {
+            let prev = right ^ self.round(round, left);
+            right = left;
+            left = prev;
+        }
+        UntypedId {
+            stamp: left,
+            random: right,
+        }
+        .typed()
+    }
+
+    // The Feistel round function.
+    fn round(&self, round: u8, half: u64) -> u64 {
+        let mut h = SipHasher24::new_with_keys(self.0.k0, self.0.k1);
+        h.write_u8(round);
+        h.write_u64(half);
+        h.finish()
+    }
+}
+
+impl<T: Entity> fmt::Display for PublicId<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            fmt,
+            "{}{}{}{}",
+            PUBLIC_PREFIX,
+            T::PREFIX,
+            DIVIDER,
+            self.inner
+        )
+    }
+}
+
+impl<T: Entity> std::str::FromStr for PublicId<T> {
+    type Err = IdParseError;
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        let prefix = format!("{}{}", PUBLIC_PREFIX, T::PREFIX);
+        let offset = encoded_offset(src, &prefix)?;
+
+        let inner = UntypedId::parse_at(src, offset)?;
+        let phantom = PhantomData;
+        Ok(PublicId { inner, phantom })
+    }
+}
+
+impl<T> PartialEq for PublicId<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.inner.eq(&other.inner)
+    }
+}
+
+impl<T> Eq for PublicId<T> {}
+
+impl<T> Clone for PublicId<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for PublicId<T> {}
+
+impl<T: Entity> Serialize for PublicId<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de, T: Entity> Deserialize<'de> for PublicId<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PublicIdStrVisitor<T>(PhantomData<T>);
+        impl<'vi, T: Entity> de::Visitor<'vi> for PublicIdStrVisitor<T> {
+            type Value = PublicId<T>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(formatter, "a public Id string")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<PublicId<T>, E> {
+                value.parse::<PublicId<T>>().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(PublicIdStrVisitor(PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ids::IdGen;
+
+    #[derive(Debug, Entity)]
+    #[entity(prefix = "ballot")]
+    struct Ballot;
+
+    #[test]
+    fn round_trips_via_public_form() {
+        let key = PublicIdKey::generate();
+        let id = IdGen::new().generate::<Ballot>();
+
+        assert_eq!(key.private(key.public(id)), id);
+    }
+
+    #[test]
+    fn round_trips_via_to_from_str() {
+        let key = PublicIdKey::generate();
+        let public = key.public(IdGen::new().generate::<Ballot>());
+
+        let s = public.to_string();
+        assert!(s.starts_with("pub-ballot."), "string: {:?}", s);
+        assert_eq!(s.parse::<PublicId<Ballot>>().expect("parse"), public);
+    }
+
+    #[test]
+    fn round_trips_via_serde_json() {
+        let key = PublicIdKey::generate();
+        let public = key.public(IdGen::new().generate::<Ballot>());
+
+        let json = serde_json::to_string(&public).expect("serde_json::to_string");
+        let public2 = serde_json::from_str(&json).expect("serde_json::from_str");
+        assert_eq!(public, public2);
+    }
+
+    #[test]
+    fn should_hide_timestamp() {
+        let key = PublicIdKey::generate();
+        let idgen = IdGen::new();
+        let a = idgen.generate::<Ballot>();
+        let b = idgen.generate::<Ballot>();
+
+        let (pa, pb) = (key.public(a), key.public(b));
+        assert_ne!(pa.inner.stamp, a.untyped().stamp);
+        // Ids created moments apart should share no obvious structure.
+        let shared = (pa.inner.stamp ^ pb.inner.stamp).leading_zeros();
+        assert!(shared < 32, "{:?} and {:?} share {} bits", pa, pb, shared);
+    }
+
+    #[test]
+    fn should_depend_on_key() {
+        let id = IdGen::new().generate::<Ballot>();
+        let a = PublicIdKey::generate().public(id);
+        let b = PublicIdKey::generate().public(id);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn should_not_parse_private_form() {
+        let id = IdGen::new().generate::<Ballot>();
+        let result = id.to_string().parse::<PublicId<Ballot>>();
+        assert!(
+            result.is_err(),
+            "Parsing {:?} should return error; got {:?}",
+            id.to_string(),
+            result
+        );
+    }
+
+    #[test]
+    fn should_not_parse_other_entity() {
+        #[derive(Debug, Entity)]
+        #[entity(prefix = "pubvoter")]
+        struct Voter;
+
+        let key = PublicIdKey::generate();
+        let public = key.public(IdGen::new().generate::<Voter>());
+        let result = public.to_string().parse::<PublicId<Ballot>>();
+        assert!(result.is_err(), "got {:?}", result);
+    }
+}
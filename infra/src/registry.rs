@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+
+use failure::Fail;
+
+use crate::ids::DIVIDER;
+
+/// Records that a type implements `Entity` with the given prefix. These are
+/// submitted by `#[derive(Entity)]`, so that we can check that prefixes are
+/// unique, and find out which type an id belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntityRegistration {
+    pub prefix: &'static str,
+    pub type_name: &'static str,
+}
+
+#[derive(Fail, Debug, Clone, PartialEq, Eq)]
+pub enum RegistryError {
+    #[fail(display = "prefix {:?} is used by each of {:?}", prefix, type_names)]
+    DuplicatePrefix {
+        prefix: &'static str,
+        type_names: Vec<&'static str>,
+    },
+    #[fail(display = "prefix {:?} of {} is not a valid prefix", prefix, type_name)]
+    InvalidPrefix {
+        prefix: &'static str,
+        type_name: &'static str,
+    },
+}
+
+inventory::collect!(EntityRegistration);
+
+impl EntityRegistration {
+    pub const fn new(prefix: &'static str, type_name: &'static str) -> Self {
+        EntityRegistration { prefix, type_name }
+    }
+}
+
+/// Returns every registered entity, in no particular order.
+pub fn entities() -> impl Iterator<Item = &'static EntityRegistration> {
+    inventory::iter::<EntityRegistration>.into_iter()
+}
+
+/// Checks that no two registered entities share a prefix, and that every
+/// prefix is non-empty and free of the id divider. This should be called
+/// at startup.
+pub fn validate() -> Result<(), RegistryError> {
+    check(entities())
+}
+
+/// Finds the entity registered with `prefix`.
+pub fn lookup_prefix(prefix: &str) -> Option<&'static EntityRegistration> {
+    entities().find(|reg| reg.prefix == prefix)
+}
+
+/// Finds the entity that a stored id, such as `ballot.0123…`, belongs to.
+pub fn lookup_id(id: &str) -> Option<&'static EntityRegistration> {
+    let prefix = id.split(DIVIDER).next()?;
+    lookup_prefix(prefix)
+}
+
+fn check<'a, I: IntoIterator<Item = &'a EntityRegistration>>(
+    registrations: I,
+) -> Result<(), RegistryError> {
+    let mut by_prefix = BTreeMap::<_, Vec<_>>::new();
+    for reg in registrations {
+        if reg.prefix.is_empty() || reg.prefix.contains(DIVIDER) {
+            return Err(RegistryError::InvalidPrefix {
+                prefix: reg.prefix,
+                type_name: reg.type_name,
+            });
+        }
+        by_prefix.entry(reg.prefix).or_default().push(reg.type_name);
+    }
+
+    for (prefix, mut type_names) in by_prefix {
+        if type_names.len() > 1 {
+            type_names.sort();
+            return Err(RegistryError::DuplicatePrefix { prefix, type_names });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::documents::DocMeta;
+    use crate::ids::{Entity, Id};
+    use serde::{Deserialize, Serialize};
+    use std::marker::PhantomData;
+
+    #[derive(Debug, Deserialize, Serialize, Entity)]
+    #[entity(prefix = "registered")]
+    struct Registered {
+        #[serde(flatten)]
+        meta: DocMeta<Registered>,
+    }
+
+    #[derive(Debug, Entity)]
+    #[entity(prefix = "wrapped")]
+    struct Wrapped<T>(PhantomData<T>);
+
+    const A: EntityRegistration = EntityRegistration::new("a", "A");
+    const B: EntityRegistration = EntityRegistration::new("b", "B");
+
+    #[test]
+    fn registered_entities_should_be_valid() {
+        validate().expect("valid registry");
+    }
+
+    #[test]
+    fn should_register_derived_entities() {
+        let reg = lookup_prefix(Registered::PREFIX).expect("registration");
+        assert!(
+            reg.type_name.ends_with("::Registered"),
+            "type name: {}",
+            reg.type_name
+        );
+    }
+
+    #[test]
+    fn should_register_generic_entities() {
+        let reg = lookup_prefix(Wrapped::<()>::PREFIX).expect("registration");
+        assert!(
+            reg.type_name.ends_with("::Wrapped"),
+            "type name: {}",
+            reg.type_name
+        );
+    }
+
+    #[test]
+    fn should_look_up_by_id() {
+        let id = Id::<Registered>::hashed("some entity");
+        let reg = lookup_id(&id.to_string()).expect("registration");
+        assert_eq!(reg.prefix, "registered");
+        assert_eq!(lookup_id("unregistered.0123"), None);
+    }
+
+    #[test]
+    fn should_accept_distinct_prefixes() {
+        assert_eq!(check(&[A, B]), Ok(()));
+    }
+
+    #[test]
+    fn should_reject_duplicate_prefixes() {
+        let other = EntityRegistration::new("a", "OtherA");
+        assert_eq!(
+            check(&[A, B, other]),
+            Err(RegistryError::DuplicatePrefix {
+                prefix: "a",
+                type_names: vec!["A", "OtherA"],
+            })
+        );
+    }
+
+    #[test]
+    fn should_reject_prefix_containing_divider() {
+        let dotted = EntityRegistration::new("a.b", "Dotted");
+        assert_eq!(
+            check(&[A, dotted]),
+            Err(RegistryError::InvalidPrefix {
+                prefix: "a.b",
+                type_name: "Dotted",
+            })
+        );
+    }
+
+    #[test]
+    fn should_reject_empty_prefix() {
+        let empty = EntityRegistration::new("", "Empty");
+        assert!(check(&[empty]).is_err());
+    }
+}
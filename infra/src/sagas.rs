@@ -0,0 +1,575 @@
+use std::mem;
+use std::time::{Duration, SystemTime};
+
+use failure::Error;
+use log::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::documents::{Envelope, HasMailBox, HasMeta};
+use crate::ids::{Entity, Id, IdGen};
+use crate::persistence::{ConcurrencyError, Documents};
+
+/// Book-keeping shared by all sagas. Saga documents should include this
+/// with `#[serde(flatten)]`, alongside their `DocMeta` and `MailBox`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SagaState<C> {
+    #[serde(rename = "_saga_status")]
+    status: SagaStatus,
+    // Milliseconds since the unix epoch, so we can compare it in queries.
+    #[serde(rename = "_timeout_at")]
+    timeout_at: Option<u64>,
+    #[serde(rename = "_compensations")]
+    compensations: Vec<C>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SagaStatus {
+    Running,
+    Completed,
+    Compensated,
+}
+
+/// A long running process that coordinates work across several documents.
+/// A saga is itself a document, which reacts to messages by updating its
+/// state and sending commands through its mailbox.
+pub trait Saga:
+    Sized + Serialize + DeserializeOwned + Entity + HasMeta<Self> + HasMailBox<Self::Command>
+{
+    type Message;
+    type Command;
+
+    /// Identifies the saga instance that should handle `envelope`. By
+    /// default, this is the message's correlation id, so that every message
+    /// in a chain reaches the same instance.
+    fn saga_id(envelope: &Envelope<Self::Message>) -> Id<Self> {
+        envelope.correlation_id.typed()
+    }
+
+    /// Creates a saga that has yet to see any messages.
+    fn start(id: Id<Self>) -> Self;
+
+    fn state(&self) -> &SagaState<Self::Command>;
+    fn state_mut(&mut self) -> &mut SagaState<Self::Command>;
+
+    fn handle(&mut self, idgen: &IdGen, envelope: &Envelope<Self::Message>) -> Result<(), Error>;
+
+    /// Called once the deadline given to `SagaState::set_timeout` has
+    /// passed. By default, we undo whatever we have done so far.
+    fn timed_out(&mut self, idgen: &IdGen) -> Result<(), Error> {
+        self.compensate(idgen);
+        Ok(())
+    }
+
+    /// Sends the recorded compensating commands, most recent first, and
+    /// marks the saga as finished.
+    fn compensate(&mut self, idgen: &IdGen) {
+        let correlation_id = self.meta().id.untyped();
+        let state = self.state_mut();
+        let compensations = mem::take(&mut state.compensations);
+        state.status = SagaStatus::Compensated;
+        state.timeout_at = None;
+
+        for cmd in compensations.into_iter().rev() {
+            self.mailbox_mut()
+                .send_correlated(idgen, correlation_id, cmd);
+        }
+    }
+}
+
+const LOAD_TIMED_OUT_SQL: &str = "SELECT id
+                                    FROM documents
+                                    WHERE (body ->> '_timeout_at') IS NOT NULL
+                                    AND (body ->> '_timeout_at')::bigint <= $2
+                                    AND split_part(id, '.', 1) = $1";
+
+impl<C> SagaState<C> {
+    pub fn new() -> Self {
+        SagaState {
+            status: SagaStatus::Running,
+            timeout_at: None,
+            compensations: Vec::new(),
+        }
+    }
+
+    pub fn status(&self) -> SagaStatus {
+        self.status
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.status != SagaStatus::Running
+    }
+
+    /// Arranges for `Saga::timed_out` to be called once `at` has passed,
+    /// replacing any previous timeout.
+    pub fn set_timeout(&mut self, at: SystemTime) {
+        let millis = at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        self.timeout_at = Some(millis);
+    }
+
+    pub fn clear_timeout(&mut self) {
+        self.timeout_at = None;
+    }
+
+    pub fn timeout_at(&self) -> Option<SystemTime> {
+        self.timeout_at
+            .map(|millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis))
+    }
+
+    /// Remembers a command that will undo a step we have taken, in case we
+    /// need to compensate later.
+    pub fn record_compensation(&mut self, cmd: C) {
+        self.compensations.push(cmd);
+    }
+
+    /// Marks the saga as having finished successfully, so there is nothing
+    /// left to compensate for.
+    pub fn complete(&mut self) {
+        self.status = SagaStatus::Completed;
+        self.timeout_at = None;
+        self.compensations.clear();
+    }
+}
+
+impl<C> Default for SagaState<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Documents {
+    /// Passes `envelope` to the saga instance it belongs to, starting a new
+    /// one if needs be. Messages that the saga has already handled, or that
+    /// arrive once it has finished, are ignored.
+    pub fn handle_saga_message<S: Saga>(
+        &self,
+        idgen: &IdGen,
+        envelope: &Envelope<S::Message>,
+    ) -> Result<(), Error> {
+        let id = S::saga_id(envelope);
+        if self.has_processed(&id, envelope)? {
+            debug!("Saga {} has already seen {}", id, envelope.id);
+            return Ok(());
+        }
+
+        let mut saga = self.load(&id)?.unwrap_or_else(|| S::start(id));
+        if saga.state().is_finished() {
+            debug!("Saga {} has finished; ignoring {}", id, envelope.id);
+            return Ok(());
+        }
+
+        saga.handle(idgen, envelope)?;
+        self.save_processed(&mut saga, envelope)?;
+
+        Ok(())
+    }
+
+    /// Calls `Saga::timed_out` on each saga of type `S` whose timeout is at
+    /// or before `now`, returning how many we found. Each saga fires at most
+    /// once per call, even if `timed_out` sets a timeout that has already
+    /// passed.
+    pub fn fire_saga_timeouts<S: Saga>(
+        &self,
+        idgen: &IdGen,
+        now: SystemTime,
+    ) -> Result<usize, Error> {
+        let now_millis = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or(0);
+        let mut fired = 0;
+
+        for id in self.load_timed_out_ids::<S>(now_millis)? {
+            // Someone else may have handled it since we looked.
+            let mut saga = match self.load(&id)? {
+                Some(saga) if matches!(saga.state().timeout_at(), Some(at) if at <= now) => saga,
+                _ => continue,
+            };
+            debug!("Saga {} timed out", id);
+            saga.state_mut().clear_timeout();
+            saga.timed_out(idgen)?;
+            match self.save(&mut saga) {
+                Ok(()) => fired += 1,
+                Err(e) if e.downcast_ref::<ConcurrencyError>().is_some() => {
+                    debug!("Saga {} changed whilst timing out; skipping", id);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(fired)
+    }
+
+    fn load_timed_out_ids<S: Saga>(&self, now_millis: i64) -> Result<Vec<Id<S>>, Error> {
+        let load = self.connection.prepare_cached(LOAD_TIMED_OUT_SQL)?;
+        let res = load.query(&[&S::PREFIX, &now_millis])?;
+
+        let mut ids = Vec::with_capacity(res.len());
+        for row in res.iter() {
+            let id: String = row.get(0);
+            match id.parse() {
+                Ok(id) => ids.push(id),
+                Err(e) => warn!("Skipping saga with malformed id {:?}: {}", id, e),
+            }
+        }
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::documents::*;
+    use crate::testing::{pool, IDGEN};
+    use std::cell::RefCell;
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    enum Event {
+        Closed,
+        Tallied,
+        Published,
+        Notified,
+        Failed,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    enum Command {
+        Tally,
+        Publish,
+        Notify,
+        DiscardTally,
+        Retract,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Entity, HasMeta)]
+    #[entity(prefix = "closing")]
+    struct Closing {
+        #[serde(flatten)]
+        meta: DocMeta<Closing>,
+        #[serde(flatten)]
+        saga: SagaState<Command>,
+        #[serde(flatten)]
+        mbox: MailBox<Command>,
+    }
+
+    impl HasMailBox<Command> for Closing {
+        fn mailbox(&self) -> &MailBox<Command> {
+            &self.mbox
+        }
+        fn mailbox_mut(&mut self) -> &mut MailBox<Command> {
+            &mut self.mbox
+        }
+    }
+
+    impl Saga for Closing {
+        type Message = Event;
+        type Command = Command;
+
+        fn start(id: Id<Self>) -> Self {
+            Closing {
+                meta: DocMeta::new_with_id(id),
+                saga: SagaState::new(),
+                mbox: MailBox::default(),
+            }
+        }
+
+        fn state(&self) -> &SagaState<Command> {
+            &self.saga
+        }
+        fn state_mut(&mut self) -> &mut SagaState<Command> {
+            &mut self.saga
+        }
+
+        fn handle(&mut self, idgen: &IdGen, envelope: &Envelope<Event>) -> Result<(), Error> {
+            match envelope.message {
+                Event::Closed => {
+                    self.mbox.send_caused_by(idgen, envelope, Command::Tally);
+                    self.saga
+                        .set_timeout(SystemTime::now() + Duration::from_secs(3600));
+                }
+                Event::Tallied => {
+                    self.mbox.send_caused_by(idgen, envelope, Command::Publish);
+                    self.saga.record_compensation(Command::DiscardTally);
+                }
+                Event::Published => {
+                    self.mbox.send_caused_by(idgen, envelope, Command::Notify);
+                    self.saga.record_compensation(Command::Retract);
+                }
+                Event::Notified => self.saga.complete(),
+                Event::Failed => self.compensate(idgen),
+            }
+            Ok(())
+        }
+    }
+
+    /// Asks to be reminded again straight away, every time.
+    #[derive(Debug, Clone, Serialize, Deserialize, Entity, HasMeta)]
+    #[entity(prefix = "reminder")]
+    struct Reminder {
+        #[serde(flatten)]
+        meta: DocMeta<Reminder>,
+        #[serde(flatten)]
+        saga: SagaState<Command>,
+        #[serde(flatten)]
+        mbox: MailBox<Command>,
+        reminded: usize,
+    }
+
+    impl HasMailBox<Command> for Reminder {
+        fn mailbox(&self) -> &MailBox<Command> {
+            &self.mbox
+        }
+        fn mailbox_mut(&mut self) -> &mut MailBox<Command> {
+            &mut self.mbox
+        }
+    }
+
+    type Rival = Box<dyn Fn(&Id<Reminder>)>;
+
+    thread_local! {
+        // Lets a test change a saga whilst we time it out.
+        static RIVAL: RefCell<Option<Rival>> = RefCell::new(None);
+    }
+
+    impl Saga for Reminder {
+        type Message = Event;
+        type Command = Command;
+
+        fn start(id: Id<Self>) -> Self {
+            Reminder {
+                meta: DocMeta::new_with_id(id),
+                saga: SagaState::new(),
+                mbox: MailBox::default(),
+                reminded: 0,
+            }
+        }
+
+        fn state(&self) -> &SagaState<Command> {
+            &self.saga
+        }
+        fn state_mut(&mut self) -> &mut SagaState<Command> {
+            &mut self.saga
+        }
+
+        fn handle(&mut self, _: &IdGen, _: &Envelope<Event>) -> Result<(), Error> {
+            self.saga.set_timeout(SystemTime::UNIX_EPOCH);
+            Ok(())
+        }
+
+        fn timed_out(&mut self, _: &IdGen) -> Result<(), Error> {
+            RIVAL.with(|rival| {
+                if let Some(rival) = rival.borrow().as_ref() {
+                    rival(&self.meta.id)
+                }
+            });
+            self.reminded += 1;
+            self.saga.set_timeout(SystemTime::UNIX_EPOCH);
+            Ok(())
+        }
+    }
+
+    fn envelope_for(id: Id<Closing>, message: Event) -> Envelope<Event> {
+        Envelope {
+            id: IDGEN.untyped(),
+            seq: 0,
+            correlation_id: id.untyped(),
+            causation_id: None,
+            message,
+        }
+    }
+
+    fn commands(saga: &Closing) -> Vec<Command> {
+        saga.mbox.outgoing().map(|e| e.message.clone()).collect()
+    }
+
+    #[test]
+    fn should_send_commands_for_each_step() -> Result<(), Error> {
+        let id = IDGEN.generate();
+        let mut saga = Closing::start(id);
+
+        for event in [Event::Closed, Event::Tallied, Event::Published]
+            .iter()
+            .cloned()
+        {
+            saga.handle(&IDGEN, &envelope_for(id, event))?;
+        }
+
+        assert_eq!(
+            commands(&saga),
+            vec![Command::Tally, Command::Publish, Command::Notify]
+        );
+        assert!(saga
+            .mbox
+            .outgoing()
+            .all(|e| e.correlation_id == id.untyped()));
+        Ok(())
+    }
+
+    #[test]
+    fn should_compensate_in_reverse_order() -> Result<(), Error> {
+        let id = IDGEN.generate();
+        let mut saga = Closing::start(id);
+
+        for event in [Event::Closed, Event::Tallied, Event::Published]
+            .iter()
+            .cloned()
+        {
+            saga.handle(&IDGEN, &envelope_for(id, event))?;
+        }
+        saga.mbox.drain().for_each(drop);
+        saga.handle(&IDGEN, &envelope_for(id, Event::Failed))?;
+
+        assert_eq!(
+            commands(&saga),
+            vec![Command::Retract, Command::DiscardTally]
+        );
+        assert_eq!(saga.saga.status(), SagaStatus::Compensated);
+        assert_eq!(saga.saga.timeout_at(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_compensate_once_completed() -> Result<(), Error> {
+        let id = IDGEN.generate();
+        let mut saga = Closing::start(id);
+
+        for event in [
+            Event::Closed,
+            Event::Tallied,
+            Event::Published,
+            Event::Notified,
+        ]
+        .iter()
+        .cloned()
+        {
+            saga.handle(&IDGEN, &envelope_for(id, event))?;
+        }
+        saga.mbox.drain().for_each(drop);
+        saga.compensate(&IDGEN);
+
+        assert_eq!(commands(&saga), vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_start_and_persist_saga() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("sagas_should_start_and_persist_saga")?;
+        let docs = pool.get()?;
+
+        let id = IDGEN.generate();
+        docs.handle_saga_message::<Closing>(&IDGEN, &envelope_for(id, Event::Closed))?;
+
+        let loaded = docs.load(&id)?.expect("saga");
+        assert_eq!(commands(&loaded), vec![Command::Tally]);
+        assert_eq!(loaded.saga.status(), SagaStatus::Running);
+        Ok(())
+    }
+
+    #[test]
+    fn should_ignore_redelivered_messages() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("sagas_should_ignore_redelivered_messages")?;
+        let docs = pool.get()?;
+
+        let id = IDGEN.generate();
+        let closed = envelope_for(id, Event::Closed);
+        docs.handle_saga_message::<Closing>(&IDGEN, &closed)?;
+        docs.handle_saga_message::<Closing>(&IDGEN, &closed)?;
+
+        let loaded = docs.load(&id)?.expect("saga");
+        assert_eq!(commands(&loaded), vec![Command::Tally]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_fire_expired_timeouts() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("sagas_should_fire_expired_timeouts")?;
+        let docs = pool.get()?;
+
+        let id = IDGEN.generate();
+        docs.handle_saga_message::<Closing>(&IDGEN, &envelope_for(id, Event::Closed))?;
+        docs.handle_saga_message::<Closing>(&IDGEN, &envelope_for(id, Event::Tallied))?;
+
+        let now = SystemTime::now();
+        assert_eq!(docs.fire_saga_timeouts::<Closing>(&IDGEN, now)?, 0);
+
+        let later = now + Duration::from_secs(7200);
+        assert_eq!(docs.fire_saga_timeouts::<Closing>(&IDGEN, later)?, 1);
+        assert_eq!(docs.fire_saga_timeouts::<Closing>(&IDGEN, later)?, 0);
+
+        let loaded = docs.load(&id)?.expect("saga");
+        assert_eq!(loaded.saga.status(), SagaStatus::Compensated);
+        assert_eq!(
+            commands(&loaded),
+            vec![Command::Tally, Command::Publish, Command::DiscardTally]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_fire_each_timeout_once_per_call() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("sagas_should_fire_each_timeout_once_per_call")?;
+        let docs = pool.get()?;
+
+        let id: Id<Reminder> = IDGEN.generate();
+        let closed = envelope_for(id.untyped().typed(), Event::Closed);
+        docs.handle_saga_message::<Reminder>(&IDGEN, &closed)?;
+
+        let now = SystemTime::now();
+        assert_eq!(docs.fire_saga_timeouts::<Reminder>(&IDGEN, now)?, 1);
+        assert_eq!(docs.fire_saga_timeouts::<Reminder>(&IDGEN, now)?, 1);
+
+        let loaded: Reminder = docs.load(&id)?.expect("saga");
+        assert_eq!(loaded.reminded, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn should_fire_other_timeouts_when_one_saga_changes() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("sagas_timeout_race")?;
+        let docs = pool.get()?;
+
+        let ids = (0..3)
+            .map(|_| {
+                let id: Id<Reminder> = IDGEN.generate();
+                let closed = envelope_for(id.untyped().typed(), Event::Closed);
+                docs.handle_saga_message::<Reminder>(&IDGEN, &closed)?;
+                Ok(id)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        docs.connection.execute(
+            "INSERT INTO documents (id, body)
+             VALUES ('reminder.bogus', '{\"_id\": \"reminder.bogus\", \"_timeout_at\": 0}')",
+            &[],
+        )?;
+
+        let raced = ids[1];
+        let rival_pool = pool.clone();
+        RIVAL.with(|rival| {
+            *rival.borrow_mut() = Some(Box::new(move |id: &Id<Reminder>| {
+                if *id == raced {
+                    let docs = rival_pool.get().expect("rival connection");
+                    let mut saga = docs.load(id).expect("load").expect("saga");
+                    docs.save::<Reminder>(&mut saga).expect("save");
+                }
+            }))
+        });
+        let fired = docs.fire_saga_timeouts::<Reminder>(&IDGEN, SystemTime::now());
+        RIVAL.with(|rival| rival.borrow_mut().take());
+
+        assert_eq!(fired?, 2);
+        for id in &ids {
+            let loaded: Reminder = docs.load(id)?.expect("saga");
+            let expected = if *id == raced { 0 } else { 1 };
+            assert_eq!(loaded.reminded, expected, "Reminder {}", id);
+        }
+        Ok(())
+    }
+}
@@ -4,10 +4,12 @@ use std::hash::{Hash, Hasher};
 use std::time::{Duration, SystemTime};
 
 use data_encoding::BASE32_DNSSEC;
-use failure::{bail, Error};
+use postgres::types::{FromSql, IsNull, ToSql, Type};
+use postgres::{accepts, to_sql_checked};
+use rand::Rng;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::ids::{Id, IdGen, IdParseError, ENCODED_BARE_ID_LEN};
+use crate::ids::{Id, IdGen, IdGenState, IdParseError, ENCODED_BARE_ID_LEN};
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
 pub struct UntypedId {
@@ -18,7 +20,9 @@ pub struct UntypedId {
 
 impl IdGen {
     pub fn untyped(&self) -> UntypedId {
-        let stamp_epoch = SystemTime::now()
+        let stamp_epoch = self
+            .clock
+            .now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .expect("now");
         let stamp_s: u64 = stamp_epoch
@@ -26,13 +30,28 @@ impl IdGen {
             .checked_mul(1000 * 1000 * 1000)
             .expect("secs * 1000,000,000");
         let stamp_ms: u64 = stamp_epoch.subsec_nanos().into();
-        let stamp = stamp_s + stamp_ms;
-        let random = rand::random();
+
+        let mut state = self.state.lock().expect("lock idgen state");
+        let stamp = state.next_stamp(stamp_s + stamp_ms);
+        let random = match &mut state.rng {
+            Some(rng) => rng.gen(),
+            None => rand::random(),
+        };
 
         UntypedId { random, stamp }
     }
 }
 
+impl IdGenState {
+    /// Returns `now`, unless we have already issued a stamp at or after it,
+    /// in which case we use the one after that.
+    fn next_stamp(&mut self, now: u64) -> u64 {
+        let stamp = std::cmp::max(now, self.last_stamp + 1);
+        self.last_stamp = stamp;
+        stamp
+    }
+}
+
 impl UntypedId {
     pub(crate) fn from_bytes(bytes: &[u8]) -> Self {
         let stamp = u64::from_be_bytes(bytes[0..8].try_into().expect("stamp bytes"));
@@ -71,23 +90,44 @@ impl UntypedId {
     pub fn random(&self) -> u64 {
         self.random
     }
-}
 
-impl std::str::FromStr for UntypedId {
-    type Err = Error;
-    fn from_str(src: &str) -> Result<Self, Self::Err> {
-        let mut bytes = [0u8; 16];
-        if src.len() != ENCODED_BARE_ID_LEN {
-            bail!(IdParseError::Unparseable);
+    /// Returns the lowest id that we could generate at `at`, so that we can
+    /// find the ids generated within a time range.
+    pub fn min_at(at: SystemTime) -> Self {
+        let stamp = at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_nanos().try_into().unwrap_or(u64::MAX))
+            .unwrap_or(0);
+        UntypedId { stamp, random: 0 }
+    }
+
+    /// Parses the encoded id that starts at `offset` in `src`, so that any
+    /// error refers to a position in the whole of `src`.
+    pub(crate) fn parse_at(src: &str, offset: usize) -> Result<Self, IdParseError> {
+        let encoded = &src.as_bytes()[offset..];
+        if encoded.len() != ENCODED_BARE_ID_LEN {
+            return Err(IdParseError::InvalidLength {
+                found: encoded.len(),
+                expected: ENCODED_BARE_ID_LEN,
+            });
         }
+
+        let mut bytes = [0u8; 16];
         BASE32_DNSSEC
-            .decode_mut(src.as_bytes(), &mut bytes)
-            .map_err(|e| failure::format_err!("{:?}", e))?;
+            .decode_mut(encoded, &mut bytes)
+            .map_err(|e| IdParseError::invalid_char(src, offset + e.error.position))?;
 
         Ok(Self::from_bytes(&bytes[..]))
     }
 }
 
+impl std::str::FromStr for UntypedId {
+    type Err = IdParseError;
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        Self::parse_at(src, 0)
+    }
+}
+
 impl fmt::Display for UntypedId {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut buf = [0u8; ENCODED_BARE_ID_LEN];
@@ -98,34 +138,84 @@ impl fmt::Display for UntypedId {
     }
 }
 
+// Binary formats get the raw 16 bytes, rather than the 26 character string.
 impl Serialize for UntypedId {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        serializer.serialize_str(&self.to_string())
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            serializer.serialize_bytes(&self.to_bytes())
+        }
     }
 }
 
 impl<'de> Deserialize<'de> for UntypedId {
     fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
-        struct IdStrVisitor;
-        impl<'vi> de::Visitor<'vi> for IdStrVisitor {
+        struct IdVisitor;
+        impl<'vi> de::Visitor<'vi> for IdVisitor {
             type Value = UntypedId;
 
             fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
-                write!(formatter, "an UntypedId string")
+                write!(formatter, "an UntypedId string or 16 bytes")
             }
 
             fn visit_str<E: de::Error>(self, value: &str) -> Result<UntypedId, E> {
                 value.parse::<UntypedId>().map_err(E::custom)
             }
+
+            fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<UntypedId, E> {
+                if value.len() != 16 {
+                    return Err(E::invalid_length(value.len(), &self));
+                }
+                Ok(UntypedId::from_bytes(value))
+            }
+        }
+
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(IdVisitor)
+        } else {
+            deserializer.deserialize_bytes(IdVisitor)
         }
+    }
+}
+
+// Stored as the same 16 big-endian bytes that we encode in strings, so that
+// the database sorts them in the same order as we do.
+impl ToSql for UntypedId {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut Vec<u8>,
+    ) -> Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        out.extend(self.to_bytes());
+        Ok(IsNull::No)
+    }
+
+    accepts!(postgres::types::UUID, postgres::types::BYTEA);
+
+    to_sql_checked!();
+}
 
-        deserializer.deserialize_str(IdStrVisitor)
+impl FromSql for UntypedId {
+    fn from_sql(
+        _ty: &Type,
+        raw: &[u8],
+    ) -> Result<Self, Box<dyn std::error::Error + 'static + Send + Sync>> {
+        if raw.len() != 16 {
+            return Err(failure::format_err!("Expected 16 bytes; got {}", raw.len())
+                .compat()
+                .into());
+        }
+        Ok(Self::from_bytes(raw))
     }
+
+    accepts!(postgres::types::UUID, postgres::types::BYTEA);
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::clock::ManualClock;
     use serde_json;
 
     #[test]
@@ -156,6 +246,35 @@ mod test {
         assert_eq!(id, id2);
     }
 
+    #[test]
+    fn round_trips_via_bincode_as_bytes() {
+        let id = UntypedId::hashed("boo");
+
+        let bytes = bincode::serialize(&id).expect("bincode::serialize");
+        // A u64 length, followed by the id itself.
+        assert_eq!(bytes.len(), 8 + 16);
+        let id2: UntypedId = bincode::deserialize(&bytes).expect("bincode::deserialize");
+        assert_eq!(id, id2);
+    }
+
+    #[test]
+    fn round_trips_via_cbor_as_bytes() {
+        let id = IdGen::new().untyped();
+
+        let bytes = serde_cbor::to_vec(&id).expect("serde_cbor::to_vec");
+        // A one byte header for a 16 byte string.
+        assert_eq!(bytes.len(), 1 + 16);
+        let id2: UntypedId = serde_cbor::from_slice(&bytes).expect("serde_cbor::from_slice");
+        assert_eq!(id, id2);
+    }
+
+    #[test]
+    fn should_reject_short_binary_form() {
+        let bytes = serde_cbor::to_vec(&serde_cbor::Value::Bytes(vec![0; 15])).expect("to_vec");
+        let result = serde_cbor::from_slice::<UntypedId>(&bytes);
+        assert!(result.is_err(), "got {:?}", result);
+    }
+
     #[test]
     fn serializes_to_string_like() {
         let id = UntypedId::hashed(&"boo");
@@ -186,6 +305,110 @@ mod test {
         assert!(id < id2 || id > id2);
     }
 
+    #[test]
+    fn should_generate_strictly_increasing_ids() {
+        let idgen = IdGen::new();
+        let ids = (0..1000).map(|_| idgen.untyped()).collect::<Vec<_>>();
+
+        for pair in ids.windows(2) {
+            assert!(pair[0] < pair[1], "{:?} < {:?}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn should_increment_stamp_when_clock_goes_backwards() {
+        let at = |nanos| SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos);
+        let clock = ManualClock::new(at(100));
+        let idgen = IdGen::with_clock(clock.clone(), 0);
+
+        assert_eq!(idgen.untyped().stamp, 100);
+        assert_eq!(idgen.untyped().stamp, 101);
+        clock.set(at(50));
+        assert_eq!(idgen.untyped().stamp, 102);
+        clock.set(at(200));
+        assert_eq!(idgen.untyped().stamp, 200);
+    }
+
+    #[test]
+    fn should_follow_injected_clock() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let clock = ManualClock::new(start);
+        let idgen = IdGen::with_clock(clock.clone(), 0);
+
+        assert_eq!(idgen.untyped().timestamp(), start);
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(idgen.untyped().timestamp(), start + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn min_at_should_bound_ids_generated_then() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let idgen = IdGen::deterministic(0, start);
+
+        let id = idgen.untyped();
+        assert!(UntypedId::min_at(start) <= id);
+        assert!(id < UntypedId::min_at(start + Duration::from_nanos(1)));
+        assert_eq!(UntypedId::min_at(start).timestamp(), start);
+    }
+
+    #[test]
+    fn deterministic_generators_should_agree() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let a = IdGen::deterministic(42, start);
+        let b = IdGen::deterministic(42, start);
+        let c = IdGen::deterministic(43, start);
+
+        let a_ids = (0..10).map(|_| a.untyped()).collect::<Vec<_>>();
+        let b_ids = (0..10).map(|_| b.untyped()).collect::<Vec<_>>();
+        let c_ids = (0..10).map(|_| c.untyped()).collect::<Vec<_>>();
+
+        assert_eq!(a_ids, b_ids);
+        assert_ne!(a_ids, c_ids);
+    }
+
+    #[test]
+    fn deterministic_generator_output_should_be_stable() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let idgen = IdGen::deterministic(42, start);
+
+        let ids = (0..3)
+            .map(|_| idgen.untyped().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            vec![
+                "2j8h43br2o0010song8tfda8f0",
+                "2j8h43br2o002q82p7sq65r3j4",
+                "2j8h43br2o00468aaheg0sb7qk",
+            ]
+        );
+    }
+
+    #[test]
+    fn should_share_ordering_between_clones_and_threads() {
+        let idgen = IdGen::new();
+        let threads = (0..4)
+            .map(|_| {
+                let idgen = idgen.clone();
+                std::thread::spawn(move || (0..1000).map(|_| idgen.untyped()).collect::<Vec<_>>())
+            })
+            .collect::<Vec<_>>();
+
+        let mut stamps = Vec::new();
+        for t in threads {
+            let ids = t.join().expect("join");
+            for pair in ids.windows(2) {
+                assert!(pair[0] < pair[1], "{:?} < {:?}", pair[0], pair[1]);
+            }
+            stamps.extend(ids.into_iter().map(|id| id.stamp));
+        }
+
+        let generated = stamps.len();
+        stamps.sort();
+        stamps.dedup();
+        assert_eq!(stamps.len(), generated);
+    }
+
     #[test]
     fn should_parse_expected_len() {
         let s = "0000000000001q5nnvfqq7krfo";
@@ -240,4 +463,22 @@ mod test {
             result,
         )
     }
+
+    #[test]
+    fn should_report_bad_character_and_length() {
+        assert_eq!(
+            "0000000000001q5nnvfqq7kr-o".parse::<UntypedId>(),
+            Err(IdParseError::InvalidChar {
+                found: '-',
+                offset: 24
+            })
+        );
+        assert_eq!(
+            "0000000000001q5nnvfqq7krfoa".parse::<UntypedId>(),
+            Err(IdParseError::InvalidLength {
+                found: 27,
+                expected: ENCODED_BARE_ID_LEN
+            })
+        );
+    }
 }
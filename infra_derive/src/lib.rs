@@ -0,0 +1,246 @@
+extern crate proc_macro;
+
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::spanned::Spanned;
+use syn::{Data, DeriveInput, Field, Fields, Lit, Meta, NestedMeta, Type};
+
+/// Implements `infra::ids::Entity`, taking the prefix from an
+/// `#[entity(prefix = "...")]` attribute, and registers the type with
+/// `infra::registry`.
+#[proc_macro_derive(Entity, attributes(entity))]
+pub fn derive_entity(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    entity(&input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// Implements `infra::documents::HasMeta`, using the struct's
+/// `#[serde(flatten)]` `DocMeta` field.
+#[proc_macro_derive(HasMeta)]
+pub fn derive_has_meta(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    has_meta(&input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn entity(input: &DeriveInput) -> syn::Result<TokenStream> {
+    let name = &input.ident;
+    let prefix = entity_prefix(input)?;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    // Generic types share a prefix between all of their instances, so we
+    // register them once, under the bare type name.
+    Ok(quote! {
+        impl #impl_generics ::infra::ids::Entity for #name #ty_generics #where_clause {
+            const PREFIX: &'static str = #prefix;
+        }
+        ::infra::inventory::submit! {
+            #![crate = ::infra]
+            ::infra::registry::EntityRegistration::new(
+                #prefix,
+                concat!(module_path!(), "::", stringify!(#name)),
+            )
+        }
+    })
+}
+
+fn has_meta(input: &DeriveInput) -> syn::Result<TokenStream> {
+    let name = &input.ident;
+    let field = meta_field(input)?;
+    let member = match &field.ident {
+        Some(ident) => quote!(#ident),
+        None => {
+            return Err(syn::Error::new(
+                field.span(),
+                "HasMeta can only be derived for structs with named fields",
+            ))
+        }
+    };
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::infra::documents::HasMeta<#name #ty_generics>
+            for #name #ty_generics #where_clause
+        {
+            fn meta(&self) -> &::infra::documents::DocMeta<Self> {
+                &self.#member
+            }
+            fn meta_mut(&mut self) -> &mut ::infra::documents::DocMeta<Self> {
+                &mut self.#member
+            }
+        }
+    })
+}
+
+fn entity_prefix(input: &DeriveInput) -> syn::Result<String> {
+    let mut prefix = None;
+    for attr in input.attrs.iter().filter(|a| a.path.is_ident("entity")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            other => return Err(syn::Error::new(other.span(), "expected #[entity(...)]")),
+        };
+        for item in list.nested.iter() {
+            match item {
+                NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("prefix") => {
+                    let value = match &nv.lit {
+                        Lit::Str(s) => s.value(),
+                        other => {
+                            return Err(syn::Error::new(other.span(), "prefix must be a string"))
+                        }
+                    };
+                    if prefix.is_some() {
+                        return Err(syn::Error::new(nv.span(), "duplicate entity prefix"));
+                    }
+                    prefix = Some(value);
+                }
+                other => {
+                    return Err(syn::Error::new(
+                        other.span(),
+                        "unknown entity attribute; expected `prefix = \"...\"`",
+                    ))
+                }
+            }
+        }
+    }
+
+    prefix.ok_or_else(|| {
+        syn::Error::new(
+            input.ident.span(),
+            "missing #[entity(prefix = \"...\")] attribute",
+        )
+    })
+}
+
+fn meta_field(input: &DeriveInput) -> syn::Result<&Field> {
+    let fields = match &input.data {
+        Data::Struct(s) => &s.fields,
+        _ => {
+            return Err(syn::Error::new(
+                input.ident.span(),
+                "HasMeta can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut found = None;
+    for field in fields.iter() {
+        if !(is_flattened(field)? && is_doc_meta(&field.ty)) {
+            continue;
+        }
+        if found.is_some() {
+            return Err(syn::Error::new(field.span(), "duplicate DocMeta field"));
+        }
+        found = Some(field);
+    }
+
+    found.ok_or_else(|| {
+        let span = match fields {
+            Fields::Named(f) => f.span(),
+            _ => input.ident.span(),
+        };
+        syn::Error::new(span, "missing #[serde(flatten)] DocMeta<Self> field")
+    })
+}
+
+fn is_flattened(field: &Field) -> syn::Result<bool> {
+    for attr in field.attrs.iter().filter(|a| a.path.is_ident("serde")) {
+        if let Meta::List(list) = attr.parse_meta()? {
+            let flatten = list.nested.iter().any(|item| match item {
+                NestedMeta::Meta(Meta::Path(p)) => p.is_ident("flatten"),
+                _ => false,
+            });
+            if flatten {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+fn is_doc_meta(ty: &Type) -> bool {
+    match ty {
+        Type::Path(p) => p
+            .path
+            .segments
+            .last()
+            .map(|seg| seg.ident == "DocMeta")
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(src: &str) -> DeriveInput {
+        syn::parse_str(src).expect("parse")
+    }
+
+    #[test]
+    fn should_find_flattened_meta_field() {
+        let input = parse(
+            "struct Doc {
+                name: String,
+                #[serde(flatten)]
+                meta: DocMeta<Doc>,
+                #[serde(flatten)]
+                mbox: MailBox<Msg>,
+            }",
+        );
+        let field = meta_field(&input).expect("meta field");
+        assert_eq!(
+            field.ident.as_ref().map(|i| i.to_string()),
+            Some("meta".into())
+        );
+    }
+
+    #[test]
+    fn should_accept_qualified_meta_type() {
+        let input = parse(
+            "struct Doc {
+                #[serde(rename = \"x\", flatten)]
+                m: infra::documents::DocMeta<Doc>,
+            }",
+        );
+        assert!(meta_field(&input).is_ok());
+    }
+
+    #[test]
+    fn should_reject_missing_meta_field() {
+        let input = parse("struct Doc { meta: DocMeta<Doc> }");
+        let err = meta_field(&input)
+            .map(|_| ())
+            .expect_err("no flattened meta");
+        assert!(err.to_string().contains("missing"), "{}", err);
+    }
+
+    #[test]
+    fn should_reject_duplicate_meta_fields() {
+        let input = parse(
+            "struct Doc {
+                #[serde(flatten)]
+                a: DocMeta<Doc>,
+                #[serde(flatten)]
+                b: DocMeta<Doc>,
+            }",
+        );
+        let err = meta_field(&input).map(|_| ()).expect_err("two metas");
+        assert!(err.to_string().contains("duplicate"), "{}", err);
+    }
+
+    #[test]
+    fn should_read_entity_prefix() {
+        let input = parse("#[entity(prefix = \"doc\")] struct Doc;");
+        assert_eq!(entity_prefix(&input).expect("prefix"), "doc");
+    }
+
+    #[test]
+    fn should_reject_missing_entity_prefix() {
+        let input = parse("struct Doc;");
+        assert!(entity_prefix(&input).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+//! Endpoints for operators, which need the token from `[admin]` as in
+//! `Authorization: Bearer <token>`.
+
+use actix_web::error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use crate::config::{AdminConfig, LogLevels};
+use crate::logging::Logging;
+
+#[derive(Clone)]
+pub struct Admin {
+    token: String,
+    logging: Logging,
+}
+
+impl Admin {
+    pub fn new(config: &AdminConfig, logging: Logging) -> Self {
+        let token = config.token.clone();
+        Admin { token, logging }
+    }
+
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        cfg.data(self.clone()).service(
+            web::scope("/admin")
+                .route("/log", web::put().to(set_log))
+                .route("/log/reload", web::post().to(reload_log)),
+        );
+    }
+
+    fn authorize(&self, req: &HttpRequest) -> Result<(), actix_web::Error> {
+        if self.token.is_empty() {
+            return Err(ErrorForbidden("Admin endpoints are disabled"));
+        }
+        let supplied = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match supplied {
+            Some(token) if constant_time_eq(token.as_bytes(), self.token.as_bytes()) => Ok(()),
+            _ => Err(ErrorUnauthorized("Invalid admin token")),
+        }
+    }
+}
+
+/// Applies the `level` and `modules` in the body over our current log
+/// configuration, until the next reload.
+fn set_log(
+    req: HttpRequest,
+    admin: web::Data<Admin>,
+    levels: web::Json<LogLevels>,
+) -> Result<HttpResponse, actix_web::Error> {
+    admin.authorize(&req)?;
+    admin.logging.apply_levels(&levels);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// As on `SIGHUP`, reads the `[env_logger]` section from our config files.
+fn reload_log(req: HttpRequest, admin: web::Data<Admin>) -> Result<HttpResponse, actix_web::Error> {
+    admin.authorize(&req)?;
+    admin.logging.reload().map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// So that how long we take does not give away how much of the token
+// someone has guessed.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{EnvLogger, Problems, Sources};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use log::{Level, Log, Metadata};
+    use std::fs;
+    use std::path::PathBuf;
+
+    const TOKEN: &str = "0123456789abcdef0123";
+
+    fn admin(token: &str) -> Admin {
+        admin_with_files(token, &[])
+    }
+
+    fn admin_with_files(token: &str, files: &[PathBuf]) -> Admin {
+        let sources = Sources::load(files).expect("load");
+        let env_logger = sources
+            .section::<EnvLogger>("env_logger", &mut Problems::default())
+            .expect("env_logger");
+        let config = AdminConfig {
+            token: token.to_string(),
+        };
+        Admin::new(&config, Logging::new(&env_logger, files))
+    }
+
+    fn set_persistence_debug(token: Option<&str>) -> test::TestRequest {
+        let req = test::TestRequest::put()
+            .uri("/admin/log")
+            .set_json(&serde_json::json!({
+                "level": "info",
+                "modules": { "infra::persistence": "debug" },
+            }));
+        match token {
+            Some(token) => req.header(AUTHORIZATION, format!("Bearer {}", token)),
+            None => req,
+        }
+    }
+
+    fn persistence_debug_enabled(admin: &Admin) -> bool {
+        let metadata = Metadata::builder()
+            .level(Level::Debug)
+            .target("infra::persistence")
+            .build();
+        admin.logging.enabled(&metadata)
+    }
+
+    #[test]
+    fn should_apply_log_levels_with_token() {
+        let admin = admin(TOKEN);
+        let mut app = test::init_service(App::new().configure(|cfg| admin.configure(cfg)));
+        assert!(!persistence_debug_enabled(&admin));
+
+        let req = set_persistence_debug(Some(TOKEN)).to_request();
+        let res = test::call_service(&mut app, req);
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(persistence_debug_enabled(&admin));
+    }
+
+    #[test]
+    fn should_reject_missing_or_wrong_token() {
+        let admin = admin(TOKEN);
+        let mut app = test::init_service(App::new().configure(|cfg| admin.configure(cfg)));
+
+        for token in &[None, Some("0123456789abcdef0124"), Some("")] {
+            let req = set_persistence_debug(*token).to_request();
+            let res = test::call_service(&mut app, req);
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "token {:?}", token);
+        }
+        assert!(!persistence_debug_enabled(&admin));
+    }
+
+    #[test]
+    fn should_only_accept_levels_and_modules() {
+        let admin = admin(TOKEN);
+        let mut app = test::init_service(App::new().configure(|cfg| admin.configure(cfg)));
+
+        let req = test::TestRequest::put()
+            .uri("/admin/log")
+            .header(AUTHORIZATION, format!("Bearer {}", TOKEN))
+            .set_json(&serde_json::json!({
+                "modules": { "infra::persistence": "debug" },
+                "format": "json",
+            }))
+            .to_request();
+        let res = test::call_service(&mut app, req);
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(!persistence_debug_enabled(&admin));
+    }
+
+    #[test]
+    fn should_reload_log_config_with_token() {
+        let path = std::env::temp_dir().join(format!("wahlen-{}-admin.toml", std::process::id()));
+        fs::write(&path, "[env_logger]\nlevel = \"info\"\n").expect("write config");
+        let admin = admin_with_files(TOKEN, std::slice::from_ref(&path));
+        let mut app = test::init_service(App::new().configure(|cfg| admin.configure(cfg)));
+
+        fs::write(
+            &path,
+            "[env_logger.modules]\n\"infra::persistence\" = \"debug\"\n",
+        )
+        .expect("write config");
+        let reload = || test::TestRequest::post().uri("/admin/log/reload");
+        let res = test::call_service(&mut app, reload().to_request());
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(!persistence_debug_enabled(&admin));
+
+        let req = reload()
+            .header(AUTHORIZATION, format!("Bearer {}", TOKEN))
+            .to_request();
+        let res = test::call_service(&mut app, req);
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(persistence_debug_enabled(&admin));
+    }
+
+    #[test]
+    fn should_be_disabled_without_token() {
+        let admin = admin("");
+        let mut app = test::init_service(App::new().configure(|cfg| admin.configure(cfg)));
+
+        let req = set_persistence_debug(Some("")).to_request();
+        let res = test::call_service(&mut app, req);
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+}
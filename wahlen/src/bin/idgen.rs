@@ -1,9 +1,18 @@
-use failure::Fallible;
+use std::str::FromStr;
 
-use chrono::{DateTime, SecondsFormat, Utc};
+use failure::{bail, Error, Fallible};
+
+use chrono::{DateTime, FixedOffset, SecondsFormat, Utc};
+use infra::any_ids::AnyId;
+use infra::clock::ManualClock;
+use infra::hash_keys::HashKey;
 use infra::ids::IdGen;
 use infra::untyped_ids::UntypedId;
+use serde_json::json;
 use structopt::StructOpt;
+use uuid::Uuid;
+
+const TSV_HEADER: &str = "prefix\ttimestamp\trandom\tid\tuuid\tuuid_v7\tulid";
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "idgen", about = "Generate Identifiers")]
@@ -12,17 +21,156 @@ enum Commands {
     Generate(Generate),
     #[structopt(name = "decompose", about = "Decompose Identifiers")]
     Decompose(Decompose),
+    #[structopt(name = "gen-key", about = "Generate a key for hashed identifiers")]
+    GenerateKey,
 }
 
 #[derive(Debug, StructOpt)]
 struct Generate {
     #[structopt(short = "n", long = "count", default_value = "1")]
     count: usize,
+    /// Entity prefix, such as election
+    #[structopt(long = "prefix")]
+    prefix: Option<String>,
+    /// Derive the id from this value, as `UntypedId::hashed` does
+    #[structopt(long = "hashed", conflicts_with = "at")]
+    hashed: Option<String>,
+    /// Generate ids as of this RFC 3339 time
+    #[structopt(long = "at", parse(try_from_str = "DateTime::parse_from_rfc3339"))]
+    at: Option<DateTime<FixedOffset>>,
 }
 
 #[derive(Debug, StructOpt)]
 struct Decompose {
-    ids: Vec<UntypedId>,
+    /// One of id, uuid, uuid-v7 or ulid
+    #[structopt(long = "from", default_value = "id")]
+    from: IdFormat,
+    /// One of text, json or tsv
+    #[structopt(long = "format", default_value = "text")]
+    format: OutputFormat,
+    ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum IdFormat {
+    Id,
+    Uuid,
+    UuidV7,
+    Ulid,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum OutputFormat {
+    Text,
+    Json,
+    Tsv,
+}
+
+impl IdFormat {
+    /// Returns the id, along with its entity prefix, if it has one.
+    fn parse(self, src: &str) -> Fallible<(Option<String>, UntypedId)> {
+        match self {
+            IdFormat::Id if src.contains('.') => {
+                let any = src.parse::<AnyId>()?;
+                Ok((Some(any.prefix().to_string()), any.untyped()))
+            }
+            IdFormat::Id => Ok((None, src.parse()?)),
+            IdFormat::Uuid => Ok((None, Uuid::parse_str(src)?.into())),
+            IdFormat::UuidV7 => Ok((None, UntypedId::from_uuid_v7(&Uuid::parse_str(src)?)?)),
+            IdFormat::Ulid => Ok((None, UntypedId::from_ulid(src)?)),
+        }
+    }
+}
+
+impl FromStr for IdFormat {
+    type Err = Error;
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        match src {
+            "id" => Ok(IdFormat::Id),
+            "uuid" => Ok(IdFormat::Uuid),
+            "uuid-v7" => Ok(IdFormat::UuidV7),
+            "ulid" => Ok(IdFormat::Ulid),
+            other => bail!("Unknown id format: {:?}", other),
+        }
+    }
+}
+
+impl OutputFormat {
+    fn format(self, prefix: Option<&str>, id: UntypedId) -> String {
+        let stamp: DateTime<Utc> = id.timestamp().into();
+        let stamp = stamp.to_rfc3339_opts(SecondsFormat::Nanos, true);
+        let random = format!("0x{:0>16x}", id.random());
+        match self {
+            OutputFormat::Text => format!(
+                "{}t:{}; r:{}; id:{}; uuid:{}; uuid-v7:{}; ulid:{}",
+                prefix
+                    .map(|prefix| format!("prefix:{}; ", prefix))
+                    .unwrap_or_default(),
+                stamp,
+                random,
+                id,
+                Uuid::from(id),
+                id.approximate_uuid_v7(),
+                id.to_ulid(),
+            ),
+            OutputFormat::Json => json!({
+                "prefix": prefix,
+                "timestamp": stamp,
+                "random": random,
+                "id": id.to_string(),
+                "uuid": Uuid::from(id).to_string(),
+                "uuid_v7": id.approximate_uuid_v7().to_string(),
+                "ulid": id.to_ulid(),
+            })
+            .to_string(),
+            OutputFormat::Tsv => format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                prefix.unwrap_or_default(),
+                stamp,
+                random,
+                id,
+                Uuid::from(id),
+                id.approximate_uuid_v7(),
+                id.to_ulid(),
+            ),
+        }
+    }
+}
+
+impl Generate {
+    fn ids(&self) -> Fallible<Vec<String>> {
+        if self.hashed.is_some() && self.count != 1 {
+            bail!("Hashed ids are always the same, so --count must be 1");
+        }
+        let idgen = match self.at {
+            Some(at) => IdGen::with_clock(ManualClock::new(at.into()), rand::random()),
+            None => IdGen::new(),
+        };
+        let mut ids = Vec::with_capacity(self.count);
+        for _ in 0..self.count {
+            let id = match &self.hashed {
+                Some(value) => UntypedId::hashed(value.as_str()),
+                None => idgen.untyped(),
+            };
+            match &self.prefix {
+                Some(prefix) => ids.push(AnyId::new(prefix, id)?.to_string()),
+                None => ids.push(id.to_string()),
+            }
+        }
+        Ok(ids)
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        match src {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "tsv" => Ok(OutputFormat::Tsv),
+            other => bail!("Unknown output format: {:?}", other),
+        }
+    }
 }
 
 fn main() -> Fallible<()> {
@@ -30,23 +178,95 @@ fn main() -> Fallible<()> {
 
     match cmd {
         Commands::Generate(opt) => {
-            let idgen = IdGen::new();
-            for _ in 0..opt.count {
-                println!("{}", idgen.untyped());
+            for id in opt.ids()? {
+                println!("{}", id);
             }
         }
         Commands::Decompose(opt) => {
-            for id in opt.ids {
-                let stamp: DateTime<Utc> = id.timestamp().into();
-                let random = id.random();
-                println!(
-                    "t:{}; r:0x{:0>16x}",
-                    stamp.to_rfc3339_opts(SecondsFormat::Nanos, true),
-                    random
-                );
+            if let OutputFormat::Tsv = opt.format {
+                println!("{}", TSV_HEADER);
             }
+            for src in opt.ids {
+                let (prefix, id) = opt.from.parse(&src)?;
+                println!("{}", opt.format.format(prefix.as_deref(), id));
+            }
+        }
+        Commands::GenerateKey => {
+            println!("{}", HashKey::generate().to_hex());
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn generate(args: &[&str]) -> Fallible<Vec<String>> {
+        let args = ["idgen", "gen"].iter().chain(args);
+        match Commands::from_iter_safe(args)? {
+            Commands::Generate(opt) => opt.ids(),
+            other => bail!("Expected gen; got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_generate_prefixed_ids_at_time() -> Fallible<()> {
+        let ids = generate(&[
+            "--prefix",
+            "election",
+            "--at",
+            "2019-09-14T12:00:00Z",
+            "-n",
+            "3",
+        ])?;
+        assert_eq!(ids.len(), 3);
+        for id in ids.iter() {
+            let (prefix, id) = IdFormat::Id.parse(id)?;
+            assert_eq!(prefix.as_deref(), Some("election"));
+            let stamp: DateTime<Utc> = id.timestamp().into();
+            // Ids from the same generator are at least a nanosecond apart.
+            assert_eq!(
+                stamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+                "2019-09-14T12:00:00.000Z"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_several_hashed_ids() -> Fallible<()> {
+        assert_eq!(generate(&["--hashed", "x"])?.len(), 1);
+        assert!(generate(&["--hashed", "x", "-n", "3"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_format_decomposed_ids() -> Fallible<()> {
+        let id = UntypedId::hashed("x");
+        let stamp = "1970-01-01T00:00:00.000000000Z";
+
+        let text = OutputFormat::Text.format(Some("ballot"), id);
+        assert!(
+            text.starts_with(&format!("prefix:ballot; t:{}; ", stamp)),
+            "{}",
+            text
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&OutputFormat::Json.format(Some("ballot"), id))?;
+        assert_eq!(json["prefix"], "ballot");
+        assert_eq!(json["timestamp"], stamp);
+        assert_eq!(json["id"], id.to_string());
+        assert_eq!(json["ulid"], id.to_ulid());
+
+        let tsv = OutputFormat::Tsv.format(None, id);
+        let fields = tsv.split('\t').collect::<Vec<_>>();
+        assert_eq!(fields.len(), TSV_HEADER.split('\t').count());
+        assert_eq!(fields[0], "");
+        assert_eq!(fields[1], stamp);
+        assert_eq!(fields[3], id.to_string());
+        Ok(())
+    }
+}
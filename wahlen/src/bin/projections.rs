@@ -0,0 +1,94 @@
+use std::path::PathBuf;
+
+use failure::Fallible;
+use structopt::StructOpt;
+use wahlen::config::{EnvLogger, Problems, Sources};
+use wahlen::logging::Logging;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "projections", about = "Maintain projections")]
+struct Opt {
+    /// Config files, where later files override earlier ones
+    #[structopt(long = "config", number_of_values = 1, parse(from_os_str))]
+    config: Vec<PathBuf>,
+    #[structopt(subcommand)]
+    cmd: Commands,
+}
+
+#[derive(Debug, PartialEq, Eq, StructOpt)]
+enum Commands {
+    #[structopt(name = "list", about = "List projections")]
+    List,
+    #[structopt(
+        name = "catch-up",
+        about = "Apply outstanding changes to all projections, and prune them"
+    )]
+    CatchUp,
+    #[structopt(name = "rebuild", about = "Rebuild a projection from scratch")]
+    Rebuild { name: String },
+}
+
+fn main() -> Fallible<()> {
+    let opt = Opt::from_args();
+
+    let sources = Sources::load(&opt.config)?;
+    let mut problems = Problems::default();
+    let config = wahlen::config::Config::from_sources(&sources, &mut problems);
+    let env_logger = sources.section::<EnvLogger>("env_logger", &mut problems);
+    let (config, env_logger) = match (config, env_logger) {
+        (Some(config), Some(env_logger)) if problems.is_empty() => (config, env_logger),
+        _ => return Err(problems.into()),
+    };
+    Logging::new(&env_logger, &opt.config).install()?;
+
+    let projections = wahlen::projections::all();
+    if let Commands::List = opt.cmd {
+        for projection in projections.iter() {
+            println!("{}", projection.name());
+        }
+        return Ok(());
+    }
+
+    let wahlen = wahlen::Wahlen::new(&config)?;
+    let docs = wahlen.pool().get()?;
+    match opt.cmd {
+        Commands::List => {}
+        Commands::CatchUp => {
+            wahlen::projections::catch_up(&docs, &projections)?;
+        }
+        Commands::Rebuild { name } => {
+            wahlen::projections::rebuild(&docs, &projections, &name)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn should_take_config_files_and_projection_name() -> Fallible<()> {
+        let opt = Opt::from_iter_safe(&[
+            "projections",
+            "--config",
+            "base.toml",
+            "--config",
+            "local.toml",
+            "rebuild",
+            "turnout",
+        ])?;
+        assert_eq!(
+            opt.config,
+            vec![PathBuf::from("base.toml"), PathBuf::from("local.toml")]
+        );
+        assert_eq!(
+            opt.cmd,
+            Commands::Rebuild {
+                name: "turnout".to_string()
+            }
+        );
+        Ok(())
+    }
+}
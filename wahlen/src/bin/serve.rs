@@ -6,31 +6,30 @@ use failure;
 extern crate log;
 use serde::Deserialize;
 use structopt;
-use toml;
 use wahlen;
 
-use std::fs::File;
-use std::io::Read;
 use std::path::PathBuf;
 
 use actix_web::{middleware::Logger, App, HttpServer};
 use failure::ResultExt;
 use structopt::StructOpt;
+use wahlen::admin::Admin;
+use wahlen::config::{AdminConfig, EnvLogger, Problems, Sources};
+use wahlen::log_context::RequestContext;
+use wahlen::logging::Logging;
+
+// As `Logger::default()`, with the request id. The logger writes this once
+// the body is done with, which is after we have left the request's context,
+// so it needs to be outside of `RequestContext` to see the header.
+const ACCESS_LOG_FORMAT: &str =
+    r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T %{x-request-id}o"#;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "serve", about = "Serve wahlen.")]
 struct Opt {
-    /// Input file
+    /// Config files, where later files override earlier ones
     #[structopt(parse(from_os_str))]
-    config: PathBuf,
-}
-
-#[derive(Deserialize, Debug)]
-struct Config {
-    #[serde(flatten)]
-    wahlen: wahlen::config::Config,
-    listener: Listener,
-    env_logger: wahlen::config::EnvLogger,
+    config: Vec<PathBuf>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -41,22 +40,36 @@ struct Listener {
 fn main() -> Result<(), failure::Error> {
     let opt = Opt::from_args();
 
-    let mut config_buf = String::new();
-    File::open(&opt.config)?.read_to_string(&mut config_buf)?;
-    let config: Config = toml::from_str(&config_buf)?;
+    let sources = Sources::load(&opt.config)?;
+    let mut problems = Problems::default();
+    let config = wahlen::config::Config::from_sources(&sources, &mut problems);
+    let listener = sources.section::<Listener>("listener", &mut problems);
+    let env_logger = sources.section::<EnvLogger>("env_logger", &mut problems);
+    let admin = AdminConfig::from_sources(&sources, &mut problems);
+    let (config, listener, env_logger, admin) = match (config, listener, env_logger, admin) {
+        (Some(config), Some(listener), Some(env_logger), Some(admin)) if problems.is_empty() => {
+            (config, listener, env_logger, admin)
+        }
+        _ => return Err(problems.into()),
+    };
 
-    config.env_logger.builder().init();
+    let logging = Logging::new(&env_logger, &opt.config);
+    logging.install()?;
+    logging.reload_on_hangup()?;
+    let admin = Admin::new(&admin, logging);
 
     let sys = actix::System::new("wahlen-app");
-    let rb = wahlen::Wahlen::new(&config.wahlen)?;
+    let rb = wahlen::Wahlen::new(&config)?;
     let factory = move || {
         App::new()
-            .wrap(Logger::default())
+            .wrap(RequestContext)
+            .wrap(Logger::new(ACCESS_LOG_FORMAT))
             .configure(|cfg| rb.configure(cfg))
+            .configure(|cfg| admin.configure(cfg))
             .service(actix_files::Files::new("/", "wahlen/static/"))
     };
     let srv = HttpServer::new(factory)
-        .bind(&config.listener.addr)
+        .bind(&listener.addr)
         .context("bind")?;
     info!("Listening on: {:?}", srv.addrs());
     srv.start();
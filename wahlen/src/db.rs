@@ -0,0 +1,237 @@
+//! Lets handlers use the document store, by taking a `PooledDocuments`
+//! argument.
+
+use std::ops::Deref;
+use std::time::Duration;
+
+use actix_web::dev::Payload;
+use actix_web::error::{ErrorInternalServerError, ErrorServiceUnavailable};
+use actix_web::{FromRequest, HttpRequest};
+use failure::Error;
+use log::*;
+use r2d2::{Pool, PooledConnection};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use infra::documents::HasMeta;
+use infra::ids::{Entity, Id};
+use infra::persistence::{DocumentConnectionManager, Documents};
+
+use crate::log_context;
+
+pub type DocumentPool = Pool<DocumentConnectionManager>;
+
+/// The pool that `PooledDocuments` come from, and how long to wait for one.
+/// Handlers run on the worker's thread, so this is short, to avoid holding
+/// up the worker's other requests when every connection is in use.
+#[derive(Clone)]
+pub struct Checkout {
+    pool: DocumentPool,
+    timeout: Duration,
+}
+
+/// A `Documents` checked out of the pool that `Wahlen` registers, which
+/// goes back to the pool once the handler is done with it. When we cannot
+/// check one out within `postgres.checkout_timeout`, such as because every
+/// connection is in use, we respond with `503 Service Unavailable`.
+///
+/// Whilst we load or save a document, we log with its id as in
+/// `log_context::document`.
+pub struct PooledDocuments(PooledConnection<DocumentConnectionManager>);
+
+impl Checkout {
+    pub fn new(pool: DocumentPool, timeout: Duration) -> Self {
+        Checkout { pool, timeout }
+    }
+
+    pub fn pool(&self) -> &DocumentPool {
+        &self.pool
+    }
+}
+
+impl PooledDocuments {
+    pub fn load<D: DeserializeOwned + Entity>(&self, id: &Id<D>) -> Result<Option<D>, Error> {
+        let _guard = log_context::document(id);
+        self.0.load(id)
+    }
+
+    pub fn save<D: Serialize + Entity + HasMeta<D>>(&self, document: &mut D) -> Result<(), Error> {
+        let _guard = log_context::document(&document.meta().id);
+        self.0.save(document)
+    }
+}
+
+impl Deref for PooledDocuments {
+    type Target = Documents;
+
+    fn deref(&self) -> &Documents {
+        &self.0
+    }
+}
+
+impl FromRequest for PooledDocuments {
+    type Error = actix_web::Error;
+    type Future = Result<Self, Self::Error>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let checkout = req
+            .app_data::<Checkout>()
+            .ok_or_else(|| ErrorInternalServerError("No document pool configured"))?;
+        let docs = checkout.pool.get_timeout(checkout.timeout).map_err(|e| {
+            warn!("Checking out documents: {}", e);
+            ErrorServiceUnavailable(e)
+        })?;
+        Ok(PooledDocuments(docs))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpResponse};
+    use infra::documents::DocMeta;
+    use infra::ids::IdGen;
+    use r2d2_postgres::{PostgresConnectionManager, TlsMode};
+    use serde::Deserialize;
+    use std::cell::RefCell;
+    use std::env;
+    use std::sync::Once;
+    use std::time::Instant;
+
+    #[derive(Debug, Deserialize, Entity)]
+    #[entity(prefix = "canary")]
+    struct Canary;
+
+    #[derive(Debug, Deserialize, Serialize, Entity, HasMeta)]
+    #[entity(prefix = "songbird")]
+    struct Songbird {
+        #[serde(flatten)]
+        meta: DocMeta<Songbird>,
+    }
+
+    /// Keeps the JSON lines logged on each thread, so that tests can look at
+    /// those from their own requests.
+    struct Captured;
+
+    thread_local! {
+        static LINES: RefCell<Vec<String>> = RefCell::default();
+    }
+
+    impl Log for Captured {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            let line = log_context::json_line("now", record);
+            LINES.with(|lines| lines.borrow_mut().push(line));
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn capture_logs() {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            log::set_boxed_logger(Box::new(Captured)).expect("install logger");
+            log::set_max_level(LevelFilter::Trace);
+        });
+        LINES.with(|lines| lines.borrow_mut().clear());
+    }
+
+    // Keeps r2d2's default `connection_timeout` of 30 seconds, so that we
+    // can see that handlers only wait for the `checkout` timeout.
+    fn pool(max_size: u32) -> Result<DocumentPool, Error> {
+        let url = env::var("POSTGRES_URL")?;
+        let manager = PostgresConnectionManager::new(&*url, TlsMode::None)?;
+        let pool = r2d2::Pool::builder()
+            .max_size(max_size)
+            .build(DocumentConnectionManager::new(manager))?;
+        pool.get()?.setup()?;
+        Ok(pool)
+    }
+
+    fn checkout(pool: &DocumentPool) -> Checkout {
+        Checkout::new(pool.clone(), Duration::from_millis(100))
+    }
+
+    fn load_canary(docs: PooledDocuments) -> Result<HttpResponse, actix_web::Error> {
+        let id = IdGen::new().generate::<Canary>();
+        let canary = docs.load(&id).map_err(ErrorInternalServerError)?;
+        Ok(HttpResponse::Ok().body(format!("{:?}", canary)))
+    }
+
+    #[test]
+    fn should_check_out_documents_for_handlers() -> Result<(), Error> {
+        let pool = pool(1)?;
+        let mut app = test::init_service(
+            App::new()
+                .data(checkout(&pool))
+                .route("/", web::get().to(load_canary)),
+        );
+
+        for _ in 0..2 {
+            let res = test::call_service(&mut app, test::TestRequest::get().to_request());
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(&test::read_body(res)[..], b"None");
+        }
+        Ok(())
+    }
+
+    fn save_songbird(docs: PooledDocuments) -> Result<HttpResponse, actix_web::Error> {
+        let mut songbird = Songbird {
+            meta: DocMeta::new_with_id(IdGen::new().generate()),
+        };
+        docs.save(&mut songbird).map_err(ErrorInternalServerError)?;
+        Ok(HttpResponse::Ok().body(songbird.meta.id.to_string()))
+    }
+
+    #[test]
+    fn should_log_with_document_id() -> Result<(), Error> {
+        capture_logs();
+        let pool = pool(1)?;
+        let mut app = test::init_service(
+            App::new()
+                .data(checkout(&pool))
+                .route("/", web::post().to(save_songbird)),
+        );
+
+        let res = test::call_service(&mut app, test::TestRequest::post().to_request());
+        assert_eq!(res.status(), StatusCode::OK);
+        let id = String::from_utf8(test::read_body(res).to_vec())?;
+
+        let lines = LINES.with(|lines| lines.borrow().clone());
+        let documents = lines
+            .iter()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).expect("json"))
+            .filter(|line| line["module"] == "infra::persistence")
+            .map(|line| line["document_id"].clone())
+            .collect::<Vec<_>>();
+        assert!(!documents.is_empty(), "{:#?}", lines);
+        assert!(documents.iter().all(|doc| *doc == *id), "{:#?}", lines);
+        Ok(())
+    }
+
+    #[test]
+    fn should_be_unavailable_when_pool_is_exhausted() -> Result<(), Error> {
+        let pool = pool(1)?;
+        let mut app = test::init_service(
+            App::new()
+                .data(checkout(&pool))
+                .route("/", web::get().to(load_canary)),
+        );
+
+        let _held = pool.get()?;
+        let started = Instant::now();
+        let res = test::call_service(&mut app, test::TestRequest::get().to_request());
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "Waited {:?} for a connection",
+            started.elapsed()
+        );
+        Ok(())
+    }
+}
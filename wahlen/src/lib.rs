@@ -1,10 +1,18 @@
 use actix_web::{web, HttpRequest, Responder};
 use failure::Error;
+use infra::hash_keys::HashKeys;
+use infra::ids::Id;
+use infra::public_ids::{PublicId, PublicIdKey};
 use log::*;
 use weft_actix::WeftResponse;
 use weft_derive::WeftRenderable;
 
+pub mod admin;
 pub mod config;
+pub mod db;
+pub mod log_context;
+pub mod logging;
+pub mod projections;
 
 #[derive(Debug, WeftRenderable)]
 #[template(path = "src/base.html")]
@@ -13,17 +21,59 @@ pub struct WithTemplate<C> {
 }
 
 #[derive(Clone)]
-pub struct Wahlen {}
+pub struct Wahlen {
+    hash_keys: HashKeys,
+    public_key: PublicIdKey,
+    checkout: db::Checkout,
+}
 
 impl Wahlen {
     pub fn new(config: &config::Config) -> Result<Self, Error> {
-        let _ = config.postgres.build()?;
+        infra::registry::validate()?;
+        let pool = config.postgres.build()?;
+        let docs = pool.get()?;
+        docs.setup()?;
+        if config.postgres.binary_keys {
+            docs.setup_binary_keys()?;
+        }
+        drop(docs);
+        let checkout = db::Checkout::new(pool, config.postgres.checkout_timeout());
+        let hash_keys = config.ids.hash_keys.clone();
+        let public_key = config.ids.public_key.clone();
+
+        Ok(Wahlen {
+            hash_keys,
+            public_key,
+            checkout,
+        })
+    }
+
+    /// The pool that handlers check documents out of, for use by other
+    /// work such as maintaining projections.
+    pub fn pool(&self) -> &db::DocumentPool {
+        self.checkout.pool()
+    }
 
-        Ok(Wahlen {})
+    /// The keys for hashed ids, such as those for voters.
+    pub fn hash_keys(&self) -> &HashKeys {
+        &self.hash_keys
+    }
+
+    /// Returns the form of `id` that handlers should show, such as in URLs
+    /// and pages. We never show the internal form, as it reveals when the
+    /// entity was created.
+    pub fn public_id<T>(&self, id: Id<T>) -> PublicId<T> {
+        self.public_key.public(id)
+    }
+
+    /// Maps an id that handlers received back to the internal form.
+    pub fn private_id<T>(&self, id: PublicId<T>) -> Id<T> {
+        self.public_key.private(id)
     }
 
     pub fn configure(&self, cfg: &mut web::ServiceConfig) {
-        cfg.service(web::resource("/").route(web::get().to_async(index)));
+        cfg.data(self.checkout.clone())
+            .service(web::resource("/").route(web::get().to_async(index)));
     }
 }
 
@@ -36,3 +86,62 @@ pub fn index(req: HttpRequest) -> Result<impl Responder, actix_web::Error> {
 
     Ok(WeftResponse::of(IndexView))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App, HttpResponse};
+    use std::env;
+    use std::fs;
+    use std::time::{Duration, Instant};
+
+    fn check_out(_: db::PooledDocuments) -> HttpResponse {
+        HttpResponse::NoContent().finish()
+    }
+
+    fn wahlen() -> Result<Wahlen, Error> {
+        let url = env::var("POSTGRES_URL")?;
+        let path = env::temp_dir().join(format!("wahlen-{}-configure.toml", std::process::id()));
+        let content = format!(
+            "[postgres]\nurl = {:?}\nmax_size = 1\ncheckout_timeout = \"100ms\"\n\
+             [ids]\npublic_key = \"9e4c27b0d3a85f16c2e07b9a41d5f836\"\n\
+             [ids.hash_keys]\ncurrent = \"5f1ba1e3b1c0a2ce5b3f1e9d8c7a6b54\"\n",
+            url
+        );
+        fs::write(&path, content)?;
+
+        let sources = config::Sources::load(&[&path])?;
+        let mut problems = config::Problems::default();
+        let config = config::Config::from_sources(&sources, &mut problems);
+        match config {
+            Some(config) if problems.is_empty() => Wahlen::new(&config),
+            _ => Err(problems.into()),
+        }
+    }
+
+    #[test]
+    fn should_check_out_documents_within_checkout_timeout() -> Result<(), Error> {
+        let wahlen = wahlen()?;
+        let mut app = test::init_service(
+            App::new()
+                .configure(|cfg| wahlen.configure(cfg))
+                .route("/check-out", web::get().to(check_out)),
+        );
+        let check_out = || test::TestRequest::get().uri("/check-out").to_request();
+
+        let res = test::call_service(&mut app, check_out());
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let _held = wahlen.checkout.pool().get()?;
+        let started = Instant::now();
+        let res = test::call_service(&mut app, check_out());
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "Waited {:?} for a connection",
+            started.elapsed()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,346 @@
+//! What we are currently doing, such as the request that we are handling,
+//! so that we can include it in each log line.
+//!
+//! Actix runs each request on a single worker thread, so we keep the
+//! context in a thread local, and re-enter it whenever a request's future
+//! is polled.
+
+use std::cell::RefCell;
+use std::fmt;
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::Error;
+use futures::future::{ok, FutureResult};
+use futures::{try_ready, Async, Future, Poll};
+use log::Record;
+use serde::Serialize;
+
+use infra::ids::{Entity, Id, IdGen};
+
+/// We use the caller's request id if they sent one, such as from a load
+/// balancer, and always return it in the response.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LEN: usize = 64;
+/// The prefix of election ids, which we also log as `election_id`.
+pub const ELECTION_PREFIX: &str = "election";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Context {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub document_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub election_id: Option<String>,
+}
+
+/// Restores the previous context when dropped.
+#[must_use = "the context is only set until the guard is dropped"]
+#[derive(Debug)]
+pub struct Guard {
+    previous: Option<Context>,
+}
+
+/// Middleware that gives each request an id, and enters a context with it
+/// whilst handling the request.
+#[derive(Debug, Clone, Default)]
+pub struct RequestContext;
+
+pub struct RequestContextMiddleware<S> {
+    service: S,
+    idgen: IdGen,
+}
+
+pub struct ContextFuture<F> {
+    context: Context,
+    inner: F,
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: &'a str,
+    module: Option<&'a str>,
+    message: String,
+    #[serde(flatten)]
+    context: Context,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Context> = RefCell::new(Context::default());
+}
+
+/// Returns a copy of the current context.
+pub fn current() -> Context {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Replaces the current context until the guard is dropped.
+pub fn enter(context: Context) -> Guard {
+    let previous = CURRENT.with(|current| current.replace(context));
+    Guard {
+        previous: Some(previous),
+    }
+}
+
+/// Adds the document that we are working on to the current context.
+pub fn document_id<I: fmt::Display>(id: I) -> Guard {
+    enter(Context {
+        document_id: Some(id.to_string()),
+        ..current()
+    })
+}
+
+/// Adds the election that we are working on to the current context.
+pub fn election_id<I: fmt::Display>(id: I) -> Guard {
+    enter(Context {
+        election_id: Some(id.to_string()),
+        ..current()
+    })
+}
+
+/// Adds the document `id` to the current context, and when it is an
+/// election, the election too.
+pub fn document<D: Entity>(id: &Id<D>) -> Guard {
+    let id = id.to_string();
+    let election_id = if D::PREFIX == ELECTION_PREFIX {
+        Some(id.clone())
+    } else {
+        current().election_id
+    };
+    enter(Context {
+        document_id: Some(id),
+        election_id,
+        ..current()
+    })
+}
+
+/// Formats `record` as a single line of JSON, along with the current
+/// context.
+pub fn json_line<T: fmt::Display>(timestamp: T, record: &Record) -> String {
+    let line = JsonRecord {
+        timestamp: timestamp.to_string(),
+        level: record.level().as_str(),
+        module: record.module_path(),
+        message: record.args().to_string(),
+        context: current(),
+    };
+    serde_json::to_string(&line).expect("serialize log record")
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            CURRENT.with(|current| current.replace(previous));
+        }
+    }
+}
+
+impl<S, B> Transform<S> for RequestContext
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestContextMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestContextMiddleware {
+            service,
+            idgen: IdGen::new(),
+        })
+    }
+}
+
+impl<S> RequestContextMiddleware<S> {
+    fn request_id(&self, req: &ServiceRequest) -> String {
+        let supplied = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN);
+        match supplied {
+            Some(id) => id.to_string(),
+            None => self.idgen.untyped().to_string(),
+        }
+    }
+}
+
+impl<S, B> Service for RequestContextMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = ContextFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.service.poll_ready()
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let context = Context {
+            request_id: Some(self.request_id(&req)),
+            ..Context::default()
+        };
+        let inner = {
+            let _guard = enter(context.clone());
+            self.service.call(req)
+        };
+        ContextFuture { context, inner }
+    }
+}
+
+impl<F, B> Future for ContextFuture<F>
+where
+    F: Future<Item = ServiceResponse<B>, Error = Error>,
+{
+    type Item = ServiceResponse<B>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut res = {
+            let _guard = enter(self.context.clone());
+            try_ready!(self.inner.poll())
+        };
+        if let Some(id) = self.context.request_id.as_ref() {
+            if let Ok(value) = HeaderValue::from_str(id) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+        }
+        Ok(Async::Ready(res))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{test, web, App, HttpResponse};
+    use log::Level;
+
+    fn request_id_handler() -> HttpResponse {
+        HttpResponse::Ok().body(current().request_id.unwrap_or_default())
+    }
+
+    #[test]
+    fn guards_should_restore_previous_context() {
+        let _request = enter(Context {
+            request_id: Some("r1".to_string()),
+            ..Context::default()
+        });
+        {
+            let _election = election_id("election.1");
+            let _document = document_id("ballot.2");
+            assert_eq!(
+                current(),
+                Context {
+                    request_id: Some("r1".to_string()),
+                    document_id: Some("ballot.2".to_string()),
+                    election_id: Some("election.1".to_string()),
+                }
+            );
+        }
+        assert_eq!(current().request_id, Some("r1".to_string()));
+        assert_eq!(current().election_id, None);
+        assert_eq!(current().document_id, None);
+    }
+
+    #[test]
+    fn should_add_elections_as_documents_and_elections() {
+        #[derive(Entity)]
+        #[entity(prefix = "election")]
+        struct Election;
+        #[derive(Entity)]
+        #[entity(prefix = "ballot")]
+        struct Ballot;
+
+        let idgen = IdGen::new();
+        let election = idgen.generate::<Election>();
+        let ballot = idgen.generate::<Ballot>();
+        let _election = document(&election);
+        assert_eq!(current().document_id, Some(election.to_string()));
+        assert_eq!(current().election_id, Some(election.to_string()));
+        {
+            let _ballot = document(&ballot);
+            assert_eq!(current().document_id, Some(ballot.to_string()));
+            assert_eq!(current().election_id, Some(election.to_string()));
+        }
+        assert_eq!(current().document_id, Some(election.to_string()));
+    }
+
+    #[test]
+    fn should_format_json_with_context() {
+        let _request = enter(Context {
+            request_id: Some("r1".to_string()),
+            ..Context::default()
+        });
+        let _election = election_id("election.1");
+        let line = json_line(
+            "2019-09-14T00:00:00Z",
+            &Record::builder()
+                .args(format_args!("Hello {}", "there"))
+                .level(Level::Info)
+                .module_path(Some("wahlen::tests"))
+                .build(),
+        );
+
+        assert!(!line.contains('\n'), "{:?}", line);
+        let value: serde_json::Value = serde_json::from_str(&line).expect("json");
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "timestamp": "2019-09-14T00:00:00Z",
+                "level": "INFO",
+                "module": "wahlen::tests",
+                "message": "Hello there",
+                "request_id": "r1",
+                "election_id": "election.1",
+            })
+        );
+    }
+
+    #[test]
+    fn should_give_each_request_an_id() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(RequestContext)
+                .route("/", web::get().to(request_id_handler)),
+        );
+
+        let res = test::call_service(&mut app, test::TestRequest::get().uri("/").to_request());
+        let header = res
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .expect("request id header")
+            .to_str()
+            .expect("ascii")
+            .to_string();
+        let body = test::read_body(res);
+        assert_eq!(header.as_bytes(), &body[..]);
+        assert!(!header.is_empty());
+        assert_eq!(current().request_id, None);
+    }
+
+    #[test]
+    fn should_use_supplied_request_id() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(RequestContext)
+                .route("/", web::get().to(request_id_handler)),
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .header(REQUEST_ID_HEADER, "from-the-balancer")
+            .to_request();
+        let body = test::read_body(test::call_service(&mut app, req));
+        assert_eq!(&body[..], b"from-the-balancer");
+    }
+}
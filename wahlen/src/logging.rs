@@ -0,0 +1,184 @@
+//! Lets us change log filters without restarting, such as to turn
+//! `infra::persistence` up to `debug` whilst we look into a problem.
+
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use failure::Error;
+use log::*;
+use signal_hook::iterator::Signals;
+
+use crate::config::{EnvLogger, LogLevels, Problems, Sources};
+
+/// Passes each record on to an `env_logger::Logger`, which we replace
+/// whenever the configuration changes.
+#[derive(Clone)]
+pub struct Logging {
+    current: Arc<RwLock<Current>>,
+    files: Arc<Vec<PathBuf>>,
+}
+
+/// The logger, along with the config that we built it from, so that we can
+/// change the filters whilst keeping the format.
+struct Current {
+    config: EnvLogger,
+    logger: env_logger::Logger,
+}
+
+impl Logging {
+    /// Configures a logger from `env_logger`, and remembers the config
+    /// `files`, so that `reload` can read them again.
+    pub fn new(env_logger: &EnvLogger, files: &[PathBuf]) -> Self {
+        let current = Arc::new(RwLock::new(Current {
+            config: env_logger.clone(),
+            logger: env_logger.builder().build(),
+        }));
+        let files = Arc::new(files.to_vec());
+        Logging { current, files }
+    }
+
+    /// Makes this the global logger.
+    pub fn install(&self) -> Result<(), Error> {
+        log::set_max_level(self.read().logger.filter());
+        log::set_boxed_logger(Box::new(self.clone()))?;
+        Ok(())
+    }
+
+    /// Replaces our filters and format with those from `env_logger`.
+    pub fn apply(&self, env_logger: &EnvLogger) {
+        self.write().replace(env_logger.clone());
+        info!("Applied log configuration: {:?}", env_logger);
+    }
+
+    /// Replaces our filters with those from `levels`, keeping the format
+    /// and any modules that `levels` does not mention.
+    pub fn apply_levels(&self, levels: &LogLevels) {
+        let env_logger = {
+            let mut current = self.write();
+            let env_logger = current.config.with_levels(levels);
+            current.replace(env_logger.clone());
+            env_logger
+        };
+        info!("Applied log configuration: {:?}", env_logger);
+    }
+
+    /// Reads the `env_logger` section from our config files again, and
+    /// applies it.
+    pub fn reload(&self) -> Result<(), Error> {
+        let sources = Sources::load(&self.files)?;
+        let mut problems = Problems::default();
+        match sources.section::<EnvLogger>("env_logger", &mut problems) {
+            Some(env_logger) => {
+                self.apply(&env_logger);
+                Ok(())
+            }
+            None => Err(problems.into()),
+        }
+    }
+
+    /// Reloads whenever the process receives `SIGHUP`.
+    pub fn reload_on_hangup(&self) -> Result<(), Error> {
+        let signals = Signals::new([signal_hook::SIGHUP])?;
+        let logging = self.clone();
+        thread::Builder::new()
+            .name("reload-logging".to_string())
+            .spawn(move || {
+                for _ in signals.forever() {
+                    info!("Reloading log configuration on hangup");
+                    if let Err(e) = logging.reload() {
+                        error!("Reloading log configuration: {}", e);
+                    }
+                }
+            })?;
+        Ok(())
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Current> {
+        self.current.read().expect("logger lock")
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, Current> {
+        self.current.write().expect("logger lock")
+    }
+}
+
+impl Current {
+    fn replace(&mut self, config: EnvLogger) {
+        let logger = config.builder().build();
+        // The `log` macros skip anything above this, before we see it.
+        log::set_max_level(logger.filter());
+        *self = Current { config, logger };
+    }
+}
+
+impl Log for Logging {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.read().logger.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.read().logger.log(record)
+    }
+
+    fn flush(&self) {
+        self.read().logger.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    fn debug_enabled(logging: &Logging, target: &str) -> bool {
+        logging.enabled(
+            &Metadata::builder()
+                .level(Level::Debug)
+                .target(target)
+                .build(),
+        )
+    }
+
+    #[test]
+    fn should_apply_new_module_filters_on_reload() -> Result<(), Error> {
+        let path = std::env::temp_dir().join(format!("wahlen-{}-reload.toml", std::process::id()));
+        let write_levels = |persistence: &str| {
+            let content = format!(
+                "[env_logger]\nlevel = \"info\"\n[env_logger.modules]\n\"infra::persistence\" = {:?}\n",
+                persistence
+            );
+            fs::write(&path, content).expect("write config")
+        };
+
+        write_levels("warn");
+        let sources = Sources::load(&[&path])?;
+        let env_logger = sources
+            .section::<EnvLogger>("env_logger", &mut Problems::default())
+            .expect("env_logger");
+        let logging = Logging::new(&env_logger, std::slice::from_ref(&path));
+        assert!(!debug_enabled(&logging, "infra::persistence"));
+
+        write_levels("debug");
+        logging.reload()?;
+        assert!(debug_enabled(&logging, "infra::persistence"));
+        assert!(!debug_enabled(&logging, "infra::outbox"));
+        Ok(())
+    }
+
+    #[test]
+    fn should_keep_filters_when_reload_fails() -> Result<(), Error> {
+        let path = std::env::temp_dir().join(format!("wahlen-{}-broken.toml", std::process::id()));
+        fs::write(&path, "[env_logger]\nlevel = \"debug\"\n")?;
+        let sources = Sources::load(&[&path])?;
+        let env_logger = sources
+            .section::<EnvLogger>("env_logger", &mut Problems::default())
+            .expect("env_logger");
+        let logging = Logging::new(&env_logger, std::slice::from_ref(&path));
+
+        fs::write(&path, "[env_logger]\nlevel = \"loud\"\n")?;
+        assert!(logging.reload().is_err());
+        assert!(debug_enabled(&logging, "wahlen"));
+        Ok(())
+    }
+}
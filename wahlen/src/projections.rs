@@ -0,0 +1,159 @@
+//! The projections that wahlen maintains, so that the `projections` command
+//! can catch them up, and rebuild them by name.
+
+use failure::{Error, Fail};
+use log::*;
+
+use infra::persistence::Documents;
+use infra::projections::Projection;
+
+#[derive(Fail, Debug, PartialEq, Eq)]
+#[fail(display = "no such projection: {:?}", _0)]
+pub struct NoSuchProjection(pub String);
+
+/// Returns every projection that we maintain. Add each new projector here,
+/// or it will neither be caught up nor rebuilt, and the changes that it
+/// needs will be pruned.
+pub fn all() -> Vec<Box<dyn Projection>> {
+    vec![]
+}
+
+/// Applies outstanding changes to each of `projections`, and then prunes the
+/// changes that all of them have applied. Returns how many were pruned.
+pub fn catch_up(docs: &Documents, projections: &[Box<dyn Projection>]) -> Result<u64, Error> {
+    for projection in projections.iter() {
+        let applied = projection.catch_up(docs)?;
+        info!("Applied {} changes to {}", applied, projection.name());
+    }
+    let names = projections.iter().map(|p| p.name()).collect::<Vec<_>>();
+    docs.prune_changes(&names)
+}
+
+/// Rebuilds the projection called `name` from scratch, returning how many
+/// documents and changes we applied.
+pub fn rebuild(
+    docs: &Documents,
+    projections: &[Box<dyn Projection>],
+    name: &str,
+) -> Result<usize, Error> {
+    let projection = projections
+        .iter()
+        .find(|p| p.name() == name)
+        .ok_or_else(|| NoSuchProjection(name.to_string()))?;
+    let applied = projection.rebuild(docs)?;
+    info!("Rebuilt {} from {} documents and changes", name, applied);
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use infra::documents::{DocMeta, HasMeta};
+    use infra::ids::{Entity, IdGen};
+    use infra::projections::Projector;
+    use postgres::transaction::Transaction;
+    use r2d2_postgres::{PostgresConnectionManager, TlsMode};
+    use serde::{Deserialize, Serialize};
+    use std::env;
+
+    use crate::db::DocumentPool;
+    use infra::persistence::DocumentConnectionManager;
+
+    #[derive(Debug, Deserialize, Serialize, Entity, HasMeta)]
+    #[entity(prefix = "pledge")]
+    struct Pledge {
+        #[serde(flatten)]
+        meta: DocMeta<Pledge>,
+    }
+
+    struct Pledges;
+
+    impl Projector for Pledges {
+        type Document = Pledge;
+
+        fn name(&self) -> &str {
+            "test_pledges"
+        }
+        fn setup(&self, t: &Transaction<'_>) -> Result<(), Error> {
+            t.batch_execute("CREATE TABLE IF NOT EXISTS test_pledges (id TEXT PRIMARY KEY)")?;
+            Ok(())
+        }
+        fn reset(&self, t: &Transaction<'_>) -> Result<(), Error> {
+            t.batch_execute("TRUNCATE test_pledges")?;
+            Ok(())
+        }
+        fn apply(&self, t: &Transaction<'_>, pledge: &Pledge) -> Result<(), Error> {
+            t.execute(
+                "INSERT INTO test_pledges (id) VALUES ($1) ON CONFLICT DO NOTHING",
+                &[&pledge.meta.id.to_string()],
+            )?;
+            Ok(())
+        }
+    }
+
+    fn pool() -> Result<DocumentPool, Error> {
+        let url = env::var("POSTGRES_URL")?;
+        let manager = PostgresConnectionManager::new(&*url, TlsMode::None)?;
+        let pool = r2d2::Pool::builder()
+            .max_size(1)
+            .build(DocumentConnectionManager::new(manager))?;
+        pool.get()?.setup()?;
+        Ok(pool)
+    }
+
+    // `Documents` keeps its connection to itself, so we look with another.
+    fn connect() -> Result<postgres::Connection, Error> {
+        let url = env::var("POSTGRES_URL")?;
+        Ok(postgres::Connection::connect(
+            &*url,
+            postgres::TlsMode::None,
+        )?)
+    }
+
+    fn count(conn: &postgres::Connection, sql: &str) -> Result<i64, Error> {
+        Ok(conn.query(sql, &[])?.get(0).get(0))
+    }
+
+    #[test]
+    fn should_rebuild_projection_by_name() -> Result<(), Error> {
+        let pool = pool()?;
+        let docs = pool.get()?;
+        let conn = connect()?;
+        let projections: Vec<Box<dyn Projection>> = vec![Box::new(Pledges)];
+
+        catch_up(&docs, &projections)?;
+        let mut pledge = Pledge {
+            meta: DocMeta::new_with_id(IdGen::new().generate()),
+        };
+        docs.save(&mut pledge)?;
+        conn.batch_execute("DELETE FROM test_pledges")?;
+
+        rebuild(&docs, &projections, "test_pledges")?;
+
+        let pledges = "SELECT count(*) FROM documents WHERE split_part(id, '.', 1) = 'pledge'";
+        assert_eq!(
+            count(&conn, "SELECT count(*) FROM test_pledges")?,
+            count(&conn, pledges)?
+        );
+        let rows = conn.query(
+            "SELECT 1 FROM test_pledges WHERE id = $1",
+            &[&pledge.meta().id.to_string()],
+        )?;
+        assert_eq!(rows.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn should_refuse_to_rebuild_unknown_projection() -> Result<(), Error> {
+        let pool = pool()?;
+        let docs = pool.get()?;
+        let projections: Vec<Box<dyn Projection>> = vec![Box::new(Pledges)];
+
+        let err = rebuild(&docs, &projections, "pledgez").expect_err("unknown projection");
+        assert_eq!(
+            err.downcast_ref::<NoSuchProjection>(),
+            Some(&NoSuchProjection("pledgez".to_string()))
+        );
+        Ok(())
+    }
+}